@@ -0,0 +1,332 @@
+use async_trait::async_trait;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::cln::ClnBackend;
+use crate::config::{
+    BackendKind, Config, ConnectionConfig, LndConfig, LndTransport, PaymentConfig,
+};
+use crate::fake::FakeBackend;
+use crate::lnd::LndBackend;
+use crate::lnd_rest::LndRestBackend;
+
+// Lightning node operations needed to serve NWC requests. Amounts are in msat,
+// hashes, preimages and pubkeys are hex encoded and times are unix timestamps.
+
+pub type BackendResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+#[derive(Debug, Clone, Default)]
+pub struct NodeInfo {
+    // Named like LND's chains: mainnet, testnet, signet, regtest or simnet
+    pub network: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Balance {
+    // Local balance of the active channels
+    pub channels_msat: i64,
+    // Local balance of the active channels minus their reserves
+    pub spendable_msat: i64,
+    // Confirmed on-chain funds
+    pub onchain_msat: i64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DecodedInvoice {
+    pub payment_hash: String,
+    // Zero when the invoice has no amount
+    pub amount_msat: u64,
+    pub created_at: u64,
+    pub expiry_secs: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Payment {
+    pub payment_hash: String,
+    pub preimage: String,
+    pub amount_msat: u64,
+    pub fees_msat: u64,
+    pub created_at: u64,
+    pub settled_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Channel {
+    // Short channel id, or the node's own identifier when it has none
+    pub channel_id: String,
+    pub remote_pubkey: String,
+    pub channel_point: String,
+    pub capacity_sat: u64,
+    pub local_balance_sat: u64,
+    pub remote_balance_sat: u64,
+    pub active: bool,
+    pub private: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PaymentState {
+    #[default]
+    InFlight,
+    Succeeded,
+    Failed,
+}
+
+// An outgoing payment as listed by the node, in any state
+#[derive(Debug, Clone, Default)]
+pub struct PaymentEntry {
+    pub payment: Payment,
+    pub invoice: Option<String>,
+    pub state: PaymentState,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InvoiceState {
+    #[default]
+    Open,
+    Accepted,
+    Settled,
+    Canceled,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Invoice {
+    pub invoice: String,
+    pub payment_hash: String,
+    pub preimage: Option<String>,
+    pub description: Option<String>,
+    pub description_hash: Option<String>,
+    pub amount_msat: u64,
+    // Amount received once settled, may differ from the requested amount
+    pub amount_paid_msat: u64,
+    pub state: InvoiceState,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub settled_at: Option<u64>,
+}
+
+impl Invoice {
+    pub fn received_msat(&self) -> u64 {
+        if self.amount_paid_msat > 0 {
+            self.amount_paid_msat
+        } else {
+            self.amount_msat
+        }
+    }
+}
+
+#[async_trait]
+pub trait WalletBackend: Send + Sync {
+    async fn get_info(&self) -> BackendResult<NodeInfo>;
+
+    async fn balance(&self) -> BackendResult<Balance>;
+
+    async fn decode_invoice(&self, invoice: &str) -> BackendResult<DecodedInvoice>;
+
+    // Current state of an outgoing payment, `None` when the node never attempted it.
+    // Answers right away, even while the payment is in flight.
+    async fn lookup_payment(&self, payment_hash: &str) -> BackendResult<Option<PaymentEntry>>;
+
+    // False when the node never paid this hash
+    async fn is_payment_succeeded(&self, payment_hash: &str) -> BackendResult<bool> {
+        let payment = self.lookup_payment(payment_hash).await?;
+        Ok(payment.is_some_and(|entry| entry.state == PaymentState::Succeeded))
+    }
+
+    // Payments fail with `payment_failed` once the node gave up on them, any other
+    // error leaves their outcome unknown
+
+    async fn pay_invoice(
+        &self,
+        invoice: &str,
+        amount_msat: Option<u64>,
+        options: &PaymentConfig,
+        max_fee_msat: Option<u64>,
+    ) -> BackendResult<Payment>;
+
+    // A random preimage is used when none is given
+    async fn pay_keysend(
+        &self,
+        pubkey: &str,
+        amount_msat: u64,
+        preimage: Option<&str>,
+        tlv_records: &[(u64, String)],
+        options: &PaymentConfig,
+        max_fee_msat: Option<u64>,
+    ) -> BackendResult<Payment>;
+
+    async fn make_invoice(
+        &self,
+        amount_msat: u64,
+        description: Option<&str>,
+        description_hash: Option<&str>,
+        expiry_secs: Option<u64>,
+    ) -> BackendResult<Invoice>;
+
+    // By payment hash or, when missing, by BOLT11 invoice
+    async fn lookup_invoice(
+        &self,
+        payment_hash: Option<&str>,
+        invoice: Option<&str>,
+    ) -> BackendResult<Invoice>;
+
+    // Resolves once the invoice is settled, fails when it is canceled
+    async fn wait_for_invoice_settlement(&self, payment_hash: &str) -> BackendResult<Invoice>;
+
+    // Listings are optional, the most recent entries come first
+    async fn list_channels(&self) -> BackendResult<Vec<Channel>> {
+        Err(unsupported("Listing channels"))
+    }
+
+    async fn list_invoices(&self, _limit: u64) -> BackendResult<Vec<Invoice>> {
+        Err(unsupported("Listing invoices"))
+    }
+
+    async fn list_payments(&self, _limit: u64) -> BackendResult<Vec<PaymentEntry>> {
+        Err(unsupported("Listing payments"))
+    }
+
+    // Channel management is optional too. The peer is connected first when its
+    // `host:port` is given, the funding channel point is returned.
+    async fn open_channel(
+        &self,
+        _pubkey: &str,
+        _host: Option<&str>,
+        _amount_sat: u64,
+        _push_sat: u64,
+        _private: bool,
+    ) -> BackendResult<String> {
+        Err(unsupported("Opening channels"))
+    }
+
+    // Returns the closing transaction id once it is broadcast
+    async fn close_channel(&self, _channel_point: &str, _force: bool) -> BackendResult<String> {
+        Err(unsupported("Closing channels"))
+    }
+}
+
+// Error of a payment that definitively failed, nothing was or will be sent
+#[derive(Debug)]
+pub struct PaymentFailed(pub String);
+
+impl fmt::Display for PaymentFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for PaymentFailed {}
+
+pub fn payment_failed<E: ToString>(reason: E) -> Box<dyn std::error::Error + Send + Sync> {
+    Box::new(PaymentFailed(reason.to_string()))
+}
+
+pub fn is_payment_failed(error: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    error.downcast_ref::<PaymentFailed>().is_some()
+}
+
+fn unsupported(operation: &str) -> Box<dyn std::error::Error + Send + Sync> {
+    Box::new(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{operation} is not supported by this backend"),
+    ))
+}
+
+pub fn from_config(cfg: &Config) -> Arc<dyn WalletBackend> {
+    match cfg.backend {
+        BackendKind::Lnd => lnd_backend(&cfg.lnd),
+        BackendKind::Cln => Arc::new(ClnBackend::new(&cfg.cln)),
+        BackendKind::Fake => Arc::new(FakeBackend::new(&cfg.fake)),
+    }
+}
+
+pub fn lnd_backend(lnd: &LndConfig) -> Arc<dyn WalletBackend> {
+    match lnd.transport {
+        LndTransport::Grpc => Arc::new(LndBackend::new(lnd)),
+        LndTransport::Rest => Arc::new(LndRestBackend::new(lnd)),
+    }
+}
+
+// Backends of the daemon's connections, with a client per configured LND node.
+// Connections with their own macaroon get their own client, kept for their next
+// requests, the others share their node's. Nodes are loaded when the daemon starts.
+pub struct Backends {
+    kind: BackendKind,
+    shared: Arc<dyn WalletBackend>,
+    // Keyed by node name, `None` being the default `[lnd]` node
+    lnd_nodes: HashMap<Option<String>, (LndConfig, Arc<dyn WalletBackend>)>,
+    macaroon_backends: Mutex<HashMap<MacaroonKey, Arc<dyn WalletBackend>>>,
+}
+
+// Node name and macaroon file of a connection with its own macaroon
+type MacaroonKey = (Option<String>, PathBuf);
+
+impl Backends {
+    pub fn from_config(cfg: &Config) -> Self {
+        let mut lnd_nodes = HashMap::new();
+        if cfg.backend == BackendKind::Lnd {
+            lnd_nodes.insert(None, (cfg.lnd.clone(), lnd_backend(&cfg.lnd)));
+            for (name, lnd) in &cfg.lnd_nodes {
+                lnd_nodes.insert(Some(name.clone()), (lnd.clone(), lnd_backend(lnd)));
+            }
+        }
+
+        Backends {
+            kind: cfg.backend,
+            shared: from_config(cfg),
+            lnd_nodes,
+            macaroon_backends: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn for_connection(
+        &self,
+        connection: &ConnectionConfig,
+    ) -> BackendResult<Arc<dyn WalletBackend>> {
+        if self.kind != BackendKind::Lnd {
+            return Ok(self.shared.clone());
+        }
+
+        let Some((lnd, backend)) = self.lnd_nodes.get(&connection.lnd_node) else {
+            return Err(Box::new(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "LND node {} is not configured",
+                    connection.lnd_node.as_deref().unwrap_or_default()
+                ),
+            )));
+        };
+        let Some(macaroon_file) = &connection.macaroon_file else {
+            return Ok(backend.clone());
+        };
+
+        let mut macaroon_backends = self.macaroon_backends.lock().unwrap();
+        let backend = macaroon_backends
+            .entry((connection.lnd_node.clone(), macaroon_file.clone()))
+            .or_insert_with(|| {
+                lnd_backend(&LndConfig {
+                    macaroon_file: macaroon_file.clone(),
+                    ..lnd.clone()
+                })
+            });
+        Ok(backend.clone())
+    }
+}
+
+// Fee limit used when the connection does not restrict it further
+pub fn default_fee_limit_msat(amount_msat: Option<u64>) -> i64 {
+    match amount_msat {
+        Some(amount) if amount > 0 => {
+            let limit = if amount <= 1_000_000 {
+                amount
+            } else {
+                amount / 20 // ~5%
+            };
+            i64::try_from(limit).unwrap_or(i64::MAX)
+        }
+        _ => i64::MAX,
+    }
+}
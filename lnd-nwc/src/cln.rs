@@ -0,0 +1,392 @@
+use async_trait::async_trait;
+use nostr_sdk::serde_json::{self, Value, json};
+use secp256k1::rand::{RngCore, rngs::OsRng};
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+use nostr_sdk::Timestamp;
+
+use crate::backend::{
+    BackendResult, Balance, DecodedInvoice, Invoice, InvoiceState, NodeInfo, Payment, PaymentEntry,
+    PaymentState, WalletBackend, payment_failed,
+};
+use crate::config::{ClnConfig, PaymentConfig};
+
+// `WalletBackend` over the Core Lightning JSON-RPC unix socket (`lightning-rpc`)
+
+const NORMAL_CHANNEL_STATE: &str = "CHANNELD_NORMAL";
+// `pay` and `keysend` errors after which no part of the payment is pending: invalid
+// params, already paid, destination failure, no route, too expensive, expired, gave up
+const PAYMENT_FAILED_CODES: [i64; 7] = [-32602, 201, 203, 205, 206, 207, 210];
+
+static REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+
+pub struct ClnBackend {
+    rpc_file: PathBuf,
+}
+
+impl ClnBackend {
+    pub fn new(config: &ClnConfig) -> Self {
+        ClnBackend {
+            rpc_file: config.rpc_file.clone(),
+        }
+    }
+
+    async fn call(&self, method: &str, params: Value) -> BackendResult<Value> {
+        let mut stream = UnixStream::connect(&self.rpc_file).await?;
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+            "method": method,
+            "params": params,
+        });
+        stream.write_all(&serde_json::to_vec(&request)?).await?;
+
+        // The response is a single JSON object, read until it is complete
+        let mut buffer = Vec::new();
+        let mut chunk = [0u8; 8192];
+        let mut response = loop {
+            let read = stream.read(&mut chunk).await?;
+            if read == 0 {
+                return Err(Box::new(other(format!("{method}: connection closed"))));
+            }
+            buffer.extend_from_slice(&chunk[..read]);
+            match serde_json::from_slice::<Value>(&buffer) {
+                Ok(response) => break response,
+                Err(e) if e.is_eof() => continue,
+                Err(e) => return Err(Box::new(e)),
+            }
+        };
+
+        if let Some(error) = response.get("error") {
+            let message = format!(
+                "{method} failed: {}",
+                error["message"].as_str().unwrap_or("unknown error")
+            );
+            let code = error["code"].as_i64().unwrap_or_default();
+            if matches!(method, "pay" | "keysend") && PAYMENT_FAILED_CODES.contains(&code) {
+                return Err(payment_failed(message));
+            }
+            return Err(Box::new(other(message)));
+        }
+        Ok(response["result"].take())
+    }
+
+    async fn find_invoice(&self, params: Value) -> BackendResult<Value> {
+        let mut invoices = self.call("listinvoices", params).await?;
+        match invoices["invoices"].get_mut(0) {
+            Some(invoice) => Ok(invoice.take()),
+            None => Err(Box::new(other("Invoice not found"))),
+        }
+    }
+
+    async fn invoice_from_cln(&self, invoice: &Value) -> BackendResult<Invoice> {
+        let bolt11 = invoice["bolt11"].as_str().unwrap_or_default().to_string();
+        // `listinvoices` has no creation date, it is part of the invoice
+        let created_at = if bolt11.is_empty() {
+            0
+        } else {
+            self.decode_invoice(&bolt11).await?.created_at
+        };
+        let state = match invoice["status"].as_str() {
+            Some("paid") => InvoiceState::Settled,
+            Some("expired") => InvoiceState::Canceled,
+            _ => InvoiceState::Open,
+        };
+
+        Ok(Invoice {
+            invoice: bolt11,
+            payment_hash: string(&invoice["payment_hash"]),
+            preimage: invoice["payment_preimage"].as_str().map(str::to_string),
+            description: invoice["description"].as_str().map(str::to_string),
+            description_hash: None,
+            amount_msat: msat(&invoice["amount_msat"]),
+            amount_paid_msat: msat(&invoice["amount_received_msat"]),
+            state,
+            created_at,
+            expires_at: invoice["expires_at"].as_u64(),
+            settled_at: invoice["paid_at"].as_u64(),
+        })
+    }
+}
+
+#[async_trait]
+impl WalletBackend for ClnBackend {
+    async fn get_info(&self) -> BackendResult<NodeInfo> {
+        let info = self.call("getinfo", json!({})).await?;
+        let network = match info["network"].as_str() {
+            Some("bitcoin") => Some("mainnet".to_string()),
+            network => network.map(str::to_string),
+        };
+
+        Ok(NodeInfo { network })
+    }
+
+    async fn balance(&self) -> BackendResult<Balance> {
+        let peer_channels = self.call("listpeerchannels", json!({})).await?;
+        let channels = peer_channels["channels"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        let active = channels
+            .iter()
+            .filter(|channel| channel["state"].as_str() == Some(NORMAL_CHANNEL_STATE));
+
+        let funds = self.call("listfunds", json!({})).await?;
+        let onchain_msat = funds["outputs"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|output| output["status"].as_str() == Some("confirmed"))
+            .map(|output| msat(&output["amount_msat"]))
+            .sum::<u64>();
+
+        Ok(Balance {
+            channels_msat: active
+                .clone()
+                .map(|channel| msat(&channel["to_us_msat"]))
+                .sum::<u64>()
+                .cast_signed(),
+            spendable_msat: active
+                .map(|channel| msat(&channel["spendable_msat"]))
+                .sum::<u64>()
+                .cast_signed(),
+            onchain_msat: onchain_msat.cast_signed(),
+        })
+    }
+
+    async fn decode_invoice(&self, invoice: &str) -> BackendResult<DecodedInvoice> {
+        let decoded = self.call("decode", json!({ "string": invoice })).await?;
+        if decoded["valid"].as_bool() == Some(false) {
+            return Err(Box::new(other("Invalid invoice")));
+        }
+
+        Ok(DecodedInvoice {
+            payment_hash: string(&decoded["payment_hash"]),
+            amount_msat: msat(&decoded["amount_msat"]),
+            created_at: decoded["created_at"].as_u64().unwrap_or(0),
+            expiry_secs: decoded["expiry"].as_u64().unwrap_or(0),
+        })
+    }
+
+    async fn lookup_payment(&self, payment_hash: &str) -> BackendResult<Option<PaymentEntry>> {
+        let pays = self
+            .call("listpays", json!({ "payment_hash": payment_hash }))
+            .await?;
+        let entries = pays["pays"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(pay_entry_from_cln)
+            .collect::<Vec<_>>();
+
+        // A hash is paid again after failed attempts, the most advanced one counts
+        let state = |wanted| entries.iter().find(|entry| entry.state == wanted).cloned();
+        Ok(state(PaymentState::Succeeded)
+            .or_else(|| state(PaymentState::InFlight))
+            .or_else(|| state(PaymentState::Failed)))
+    }
+
+    // CLN has no equivalent for the max parts, outgoing channels, last hop
+    // and self payment options, only the timeout is applied
+    async fn pay_invoice(
+        &self,
+        invoice: &str,
+        amount_msat: Option<u64>,
+        options: &PaymentConfig,
+        max_fee_msat: Option<u64>,
+    ) -> BackendResult<Payment> {
+        let mut params = json!({ "bolt11": invoice });
+        if let Some(amount_msat) = amount_msat {
+            // Only accepted for invoices without an amount
+            if self.decode_invoice(invoice).await?.amount_msat == 0 {
+                params["amount_msat"] = json!(amount_msat);
+            }
+        }
+        if let Some(max_fee_msat) = max_fee_msat {
+            params["maxfee"] = json!(max_fee_msat);
+        }
+        if let Some(timeout) = options.timeout_seconds {
+            params["retry_for"] = json!(timeout);
+        }
+
+        let payment = self.call("pay", params).await?;
+        payment_from_cln(&payment)
+    }
+
+    async fn pay_keysend(
+        &self,
+        pubkey: &str,
+        amount_msat: u64,
+        preimage: Option<&str>,
+        tlv_records: &[(u64, String)],
+        options: &PaymentConfig,
+        max_fee_msat: Option<u64>,
+    ) -> BackendResult<Payment> {
+        if preimage.is_some() {
+            return Err(Box::new(other(
+                "Core Lightning does not support custom keysend preimages",
+            )));
+        }
+
+        let mut params = json!({ "destination": pubkey, "amount_msat": amount_msat });
+        if !tlv_records.is_empty() {
+            let tlvs = tlv_records
+                .iter()
+                .map(|(typ, value)| {
+                    let value = if hex::decode(value).is_ok() {
+                        value.clone()
+                    } else {
+                        hex::encode(value)
+                    };
+                    (typ.to_string(), Value::String(value))
+                })
+                .collect::<serde_json::Map<_, _>>();
+            params["extratlvs"] = Value::Object(tlvs);
+        }
+        if let Some(max_fee_msat) = max_fee_msat {
+            params["maxfee"] = json!(max_fee_msat);
+        }
+        if let Some(timeout) = options.timeout_seconds {
+            params["retry_for"] = json!(timeout);
+        }
+
+        let payment = self.call("keysend", params).await?;
+        payment_from_cln(&payment)
+    }
+
+    async fn make_invoice(
+        &self,
+        amount_msat: u64,
+        description: Option<&str>,
+        description_hash: Option<&str>,
+        expiry_secs: Option<u64>,
+    ) -> BackendResult<Invoice> {
+        // CLN computes the hash itself and needs the full description for it
+        if description_hash.is_some() {
+            return Err(Box::new(other(
+                "Core Lightning does not support invoices from a description hash",
+            )));
+        }
+
+        let mut preimage = [0u8; 32];
+        OsRng.fill_bytes(&mut preimage);
+        let mut label = [0u8; 8];
+        OsRng.fill_bytes(&mut label);
+
+        let mut params = json!({
+            "amount_msat": amount_msat,
+            "label": format!("lnd-nwc-{}", hex::encode(label)),
+            "description": description.unwrap_or_default(),
+            "preimage": hex::encode(preimage),
+        });
+        if let Some(expiry_secs) = expiry_secs {
+            params["expiry"] = json!(expiry_secs);
+        }
+
+        let invoice = self.call("invoice", params).await?;
+        Ok(Invoice {
+            invoice: string(&invoice["bolt11"]),
+            payment_hash: string(&invoice["payment_hash"]),
+            preimage: Some(hex::encode(preimage)),
+            description: description.map(str::to_string),
+            amount_msat,
+            created_at: Timestamp::now().as_secs(),
+            expires_at: invoice["expires_at"].as_u64(),
+            ..Default::default()
+        })
+    }
+
+    async fn lookup_invoice(
+        &self,
+        payment_hash: Option<&str>,
+        invoice: Option<&str>,
+    ) -> BackendResult<Invoice> {
+        let params = match (payment_hash, invoice) {
+            (Some(payment_hash), _) => json!({ "payment_hash": payment_hash }),
+            (None, Some(invoice)) => json!({ "invstring": invoice }),
+            (None, None) => {
+                return Err(Box::new(other("Missing payment hash or payment request")));
+            }
+        };
+
+        let invoice = self.find_invoice(params).await?;
+        self.invoice_from_cln(&invoice).await
+    }
+
+    async fn wait_for_invoice_settlement(&self, payment_hash: &str) -> BackendResult<Invoice> {
+        let invoice = self
+            .find_invoice(json!({ "payment_hash": payment_hash }))
+            .await?;
+        let label = string(&invoice["label"]);
+
+        // Answers once the invoice is paid, fails once it expires
+        let invoice = self.call("waitinvoice", json!({ "label": label })).await?;
+        self.invoice_from_cln(&invoice).await
+    }
+}
+
+fn payment_from_cln(payment: &Value) -> BackendResult<Payment> {
+    match payment["status"].as_str() {
+        Some("complete") => {}
+        Some("failed") => return Err(payment_failed("Payment failed with status failed")),
+        _ => {
+            return Err(Box::new(other(format!(
+                "Payment failed with status {}",
+                payment["status"]
+            ))));
+        }
+    }
+
+    let amount_msat = msat(&payment["amount_msat"]);
+    let created_at = payment["created_at"].as_f64().unwrap_or(0.0) as u64;
+    Ok(Payment {
+        payment_hash: string(&payment["payment_hash"]),
+        preimage: string(&payment["payment_preimage"]),
+        amount_msat,
+        fees_msat: msat(&payment["amount_sent_msat"]).saturating_sub(amount_msat),
+        created_at,
+        settled_at: Some(Timestamp::now().as_secs()),
+    })
+}
+
+// An entry of `listpays`
+fn pay_entry_from_cln(pay: &Value) -> PaymentEntry {
+    let amount_msat = msat(&pay["amount_msat"]);
+    PaymentEntry {
+        payment: Payment {
+            payment_hash: string(&pay["payment_hash"]),
+            preimage: string(&pay["preimage"]),
+            amount_msat,
+            fees_msat: msat(&pay["amount_sent_msat"]).saturating_sub(amount_msat),
+            created_at: pay["created_at"].as_u64().unwrap_or(0),
+            settled_at: pay["completed_at"].as_u64(),
+        },
+        invoice: pay["bolt11"].as_str().map(str::to_string),
+        state: match pay["status"].as_str() {
+            Some("complete") => PaymentState::Succeeded,
+            Some("failed") => PaymentState::Failed,
+            _ => PaymentState::InFlight,
+        },
+    }
+}
+
+// Amounts are numbers, older versions return strings like "1000msat"
+fn msat(value: &Value) -> u64 {
+    match value {
+        Value::Number(number) => number.as_u64().unwrap_or(0),
+        Value::String(text) => text.trim_end_matches("msat").parse().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+fn string(value: &Value) -> String {
+    value.as_str().unwrap_or_default().to_string()
+}
+
+fn other<E: ToString>(err: E) -> io::Error {
+    io::Error::other(err.to_string())
+}
@@ -1,4 +1,3 @@
-use confy;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::PathBuf;
@@ -8,18 +7,178 @@ pub struct NostrConfig {
     pub secret: String,
 }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+// Present once secrets have been encrypted with a passphrase (see `secrets`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptionConfig {
+    pub salt: String,
+    pub check: String,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct LndConfig {
     pub uri: String,
     pub cert_file: PathBuf,
     pub macaroon_file: PathBuf,
+    #[serde(default)]
+    pub transport: LndTransport,
+}
+
+// API used to reach LND, `uri` is the gRPC host:port or the REST base URL
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LndTransport {
+    #[default]
+    Grpc,
+    Rest,
+}
+
+// Lightning implementation serving the NWC requests
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendKind {
+    #[default]
+    Lnd,
+    // Core Lightning, see `cln::ClnBackend`
+    Cln,
+    // In-memory node, see `fake::FakeBackend`
+    Fake,
+}
+
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct ClnConfig {
+    // The node's `lightning-rpc` unix socket
+    pub rpc_file: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FakeConfig {
+    pub balance_msat: u64,
+    pub network: String,
+    // Delay added to every call
+    pub latency_ms: u64,
+    // Invoices are paid by a simulated payer after this delay
+    pub settle_invoices_after_secs: Option<u64>,
+    // Every payment fails with this message
+    pub payment_failure: Option<String>,
+}
+
+impl Default for FakeConfig {
+    fn default() -> Self {
+        FakeConfig {
+            balance_msat: 1_000_000_000,
+            network: "regtest".to_string(),
+            latency_ms: 0,
+            settle_invoices_after_secs: None,
+            payment_failure: None,
+        }
+    }
+}
+
+// Routing options applied to every payment made through a connection
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct PaymentConfig {
+    pub timeout_seconds: Option<u32>,
+    pub max_parts: Option<u32>,
+    #[serde(default)]
+    pub outgoing_chan_ids: Vec<u64>,
+    pub last_hop_pubkey: Option<String>,
+    #[serde(default)]
+    pub allow_self_payment: bool,
+}
+
+// How `get_balance` is computed for a connection
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceMode {
+    // Local channel balance minus channel reserves
+    Spendable,
+    // Local channel balance
+    #[default]
+    Total,
+    // Local channel balance plus confirmed on-chain funds
+    WithOnchain,
+    // Spendable balance, limited to `cap_msat`
+    Capped,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BalanceConfig {
+    #[serde(default)]
+    pub mode: BalanceMode,
+    pub cap_msat: Option<u64>,
+}
+
+// A NWC connection, keyed by name. Only the client's public key is stored,
+// its secret is shown once when the connection is created.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionConfig {
+    #[serde(default)]
+    pub client_pubkey: String,
+    #[serde(default)]
+    pub relays: Vec<String>,
+    #[serde(default)]
+    pub created_at: u64,
+    // Requests are refused while disabled
+    #[serde(default)]
+    pub disabled: bool,
+    // Unix timestamp after which the daemon refuses the connection's requests
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    // Isolate the connection on its own balance ledger
+    #[serde(default)]
+    pub sub_wallet: bool,
+    // Named node of `lnd_nodes` serving the connection, the `[lnd]` one when unset
+    #[serde(default)]
+    pub lnd_node: Option<String>,
+    // Macaroon used for the connection's requests instead of the node's, see `lnd bake-macaroon`
+    #[serde(default)]
+    pub macaroon_file: Option<PathBuf>,
+    // Allows the channel management methods, see `nwc_types::ADMIN_METHODS`
+    #[serde(default)]
+    pub admin: bool,
+    #[serde(default)]
+    pub payment: PaymentConfig,
+    #[serde(default)]
+    pub balance: BalanceConfig,
+}
+
+impl ConnectionConfig {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
 }
 
 #[derive(Default, Debug, Serialize, Deserialize)]
 pub struct Config {
     pub nostr: NostrConfig,
+    // Full connection URIs from older versions, see `uri_config::migrate_legacy_uris`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     pub uris: HashMap<String, String>,
     pub lnd: LndConfig,
+    // Further LND nodes by name, chosen per connection
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub lnd_nodes: HashMap<String, LndConfig>,
+    #[serde(default)]
+    pub backend: BackendKind,
+    #[serde(default)]
+    pub cln: ClnConfig,
+    #[serde(default)]
+    pub fake: FakeConfig,
+    #[serde(default)]
+    pub connections: HashMap<String, ConnectionConfig>,
+    pub database_file: Option<PathBuf>,
+    pub encryption: Option<EncryptionConfig>,
+}
+
+impl Config {
+    // The `[lnd]` node when no name is given
+    pub fn lnd_node(&self, name: Option<&str>) -> Option<&LndConfig> {
+        match name {
+            Some(name) => self.lnd_nodes.get(name),
+            None => Some(&self.lnd),
+        }
+    }
 }
 
 // Config is stored in
@@ -34,3 +193,14 @@ pub fn load_config() -> Config {
 pub fn store_config(config: &Config) {
     confy::store("lnd-nwc", None, config).expect("Could not save the config")
 }
+
+// Moves the config, and the database next to it, to a directory of this test run
+#[cfg(test)]
+pub fn use_test_config_dir() {
+    static TEST_CONFIG_DIR: std::sync::Once = std::sync::Once::new();
+    TEST_CONFIG_DIR.call_once(|| {
+        let dir = std::env::temp_dir().join(format!("lnd-nwc-test-{}", std::process::id()));
+        // Every test calls this before reading the environment
+        unsafe { std::env::set_var("XDG_CONFIG_HOME", dir) };
+    });
+}
@@ -0,0 +1,247 @@
+use rusqlite::{Connection, params};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+
+use nostr_sdk::Timestamp;
+use serde::Serialize;
+
+use crate::config::load_config;
+
+pub type DbResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+// Health of a relay as last seen by the daemon
+#[derive(Debug, Clone, Serialize)]
+pub struct RelayRecord {
+    pub url: String,
+    pub status: String,
+    pub last_event_at: Option<u64>,
+    pub error_count: u64,
+    pub updated_at: u64,
+}
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS requests (
+    event_id TEXT PRIMARY KEY,
+    connection TEXT NOT NULL,
+    method TEXT,
+    content TEXT NOT NULL,
+    received_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS responses (
+    request_id TEXT PRIMARY KEY,
+    connection TEXT NOT NULL,
+    result_type TEXT NOT NULL,
+    error_code TEXT,
+    content TEXT NOT NULL,
+    sent_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS transactions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    connection TEXT NOT NULL,
+    transaction_type TEXT NOT NULL,
+    state TEXT NOT NULL,
+    invoice TEXT,
+    description TEXT,
+    payment_hash TEXT,
+    preimage TEXT,
+    amount_msat INTEGER NOT NULL,
+    fees_msat INTEGER NOT NULL DEFAULT 0,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER,
+    settled_at INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS relays (
+    url TEXT PRIMARY KEY,
+    status TEXT NOT NULL,
+    last_event_at INTEGER,
+    error_count INTEGER NOT NULL DEFAULT 0,
+    updated_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS requests_connection ON requests (connection, received_at);
+CREATE INDEX IF NOT EXISTS transactions_connection ON transactions (connection, created_at);
+CREATE INDEX IF NOT EXISTS transactions_payment_hash ON transactions (payment_hash);
+";
+
+// Database is stored next to the config file unless `database_file` is set
+pub fn database_path() -> DbResult<PathBuf> {
+    let cfg = load_config();
+    match cfg.database_file {
+        Some(path) => Ok(path),
+        None => {
+            let config_file = confy::get_configuration_file_path("lnd-nwc", None)?;
+            Ok(config_file.with_file_name("lnd-nwc.sqlite"))
+        }
+    }
+}
+
+// Connection shared by every call of the process, opened on first use
+static DATABASE: OnceLock<Mutex<Connection>> = OnceLock::new();
+
+pub fn open() -> DbResult<MutexGuard<'static, Connection>> {
+    let database = match DATABASE.get() {
+        Some(database) => database,
+        None => {
+            let conn = connect()?;
+            // A connection opened concurrently by another thread wins, this one is dropped
+            DATABASE.get_or_init(|| Mutex::new(conn))
+        }
+    };
+    Ok(database.lock().map_err(|e| e.to_string())?)
+}
+
+fn connect() -> DbResult<Connection> {
+    let path = database_path()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let conn = Connection::open(&path)?;
+    // The CLI and the daemon write the same file
+    conn.busy_timeout(Duration::from_secs(5))?;
+    conn.execute_batch(SCHEMA)?;
+    Ok(conn)
+}
+
+// Returns false when the request was already recorded
+pub fn record_request(
+    event_id: &str,
+    connection: &str,
+    method: Option<&str>,
+    content: &str,
+) -> DbResult<bool> {
+    let conn = open()?;
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO requests (event_id, connection, method, content, received_at)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            event_id,
+            connection,
+            method,
+            content,
+            Timestamp::now().as_secs() as i64
+        ],
+    )?;
+    Ok(inserted > 0)
+}
+
+pub fn record_response(
+    request_id: &str,
+    connection: &str,
+    result_type: &str,
+    error_code: Option<&str>,
+    content: &str,
+) -> DbResult<()> {
+    let conn = open()?;
+    conn.execute(
+        "INSERT OR REPLACE INTO responses (request_id, connection, result_type, error_code, content, sent_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            request_id,
+            connection,
+            result_type,
+            error_code,
+            content,
+            Timestamp::now().as_secs() as i64
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn last_request_at(connection: &str) -> DbResult<Option<u64>> {
+    let conn = open()?;
+    let last: Option<i64> = conn.query_row(
+        "SELECT MAX(received_at) FROM requests WHERE connection = ?1",
+        params![connection],
+        |row| row.get(0),
+    )?;
+    Ok(last.map(|t| t as u64))
+}
+
+// Moves the history of a connection to its new name
+pub fn rename_connection(old_name: &str, new_name: &str) -> DbResult<()> {
+    move_history(old_name, new_name)
+}
+
+// Detaches the history, ledger included, from a name that is no longer used so a
+// connection created or renamed with it later starts empty. The rows are kept.
+pub fn archive_connection(name: &str) -> DbResult<()> {
+    move_history(
+        name,
+        &format!("{name} (removed {})", Timestamp::now().as_secs()),
+    )
+}
+
+fn move_history(old_name: &str, new_name: &str) -> DbResult<()> {
+    let mut conn = open()?;
+    let tx = conn.transaction()?;
+    for table in ["requests", "responses", "transactions"] {
+        tx.execute(
+            &format!("UPDATE {table} SET connection = ?2 WHERE connection = ?1"),
+            params![old_name, new_name],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// Forgets the relays of a previous daemon run
+pub fn reset_relays() -> DbResult<()> {
+    let conn = open()?;
+    conn.execute("DELETE FROM relays", [])?;
+    Ok(())
+}
+
+pub fn record_relay_status(url: &str, status: &str) -> DbResult<()> {
+    let conn = open()?;
+    conn.execute(
+        "INSERT INTO relays (url, status, updated_at) VALUES (?1, ?2, ?3)
+         ON CONFLICT (url) DO UPDATE SET status = excluded.status, updated_at = excluded.updated_at",
+        params![url, status, Timestamp::now().as_secs() as i64],
+    )?;
+    Ok(())
+}
+
+pub fn record_relay_event(url: &str) -> DbResult<()> {
+    let conn = open()?;
+    conn.execute(
+        "INSERT INTO relays (url, status, last_event_at, updated_at) VALUES (?1, 'connected', ?2, ?2)
+         ON CONFLICT (url) DO UPDATE SET last_event_at = excluded.last_event_at",
+        params![url, Timestamp::now().as_secs() as i64],
+    )?;
+    Ok(())
+}
+
+pub fn record_relay_error(url: &str) -> DbResult<()> {
+    let conn = open()?;
+    conn.execute(
+        "INSERT INTO relays (url, status, error_count, updated_at) VALUES (?1, 'unknown', 1, ?2)
+         ON CONFLICT (url) DO UPDATE SET error_count = error_count + 1",
+        params![url, Timestamp::now().as_secs() as i64],
+    )?;
+    Ok(())
+}
+
+pub fn relay_records() -> DbResult<Vec<RelayRecord>> {
+    let conn = open()?;
+    let mut statement = conn.prepare(
+        "SELECT url, status, last_event_at, error_count, updated_at FROM relays ORDER BY url",
+    )?;
+    let records = statement
+        .query_map([], |row| {
+            Ok(RelayRecord {
+                url: row.get(0)?,
+                status: row.get(1)?,
+                last_event_at: row.get::<_, Option<i64>>(2)?.map(|t| t as u64),
+                error_count: row.get::<_, i64>(3)? as u64,
+                updated_at: row.get::<_, i64>(4)? as u64,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(records)
+}
@@ -0,0 +1,332 @@
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use nostr_sdk::Timestamp;
+
+use crate::backend::{
+    BackendResult, Balance, DecodedInvoice, Invoice, InvoiceState, NodeInfo, Payment, PaymentEntry,
+    PaymentState, WalletBackend, payment_failed,
+};
+use crate::config::{FakeConfig, PaymentConfig};
+
+// In-memory node for demos and offline runs. Preimages are derived from a counter so
+// the same sequence of calls always produces the same invoices and payments.
+
+const SETTLEMENT_POLL_MILLIS: u64 = 100;
+
+pub struct FakeBackend {
+    config: FakeConfig,
+    invoice_prefix: String,
+    state: Arc<Mutex<FakeState>>,
+}
+
+#[derive(Default)]
+struct FakeState {
+    balance_msat: i64,
+    counter: u64,
+    invoices: HashMap<String, Invoice>,
+    payments: HashMap<String, Payment>,
+}
+
+// Fake invoices start with the BOLT11 currency of the configured network so they
+// pass the network check of the requests
+fn invoice_prefix(network: &str) -> String {
+    let currency = match network {
+        "mainnet" => "bc",
+        "testnet" => "tb",
+        "signet" => "tbs",
+        "simnet" => "sb",
+        _ => "bcrt",
+    };
+    format!("ln{currency}1fake")
+}
+
+impl FakeBackend {
+    pub fn new(config: &FakeConfig) -> Self {
+        let state = FakeState {
+            balance_msat: config.balance_msat.cast_signed(),
+            ..Default::default()
+        };
+        FakeBackend {
+            config: config.clone(),
+            invoice_prefix: invoice_prefix(&config.network),
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    async fn simulate_latency(&self) {
+        if self.config.latency_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(self.config.latency_ms)).await;
+        }
+    }
+
+    fn next_preimage(&self) -> Vec<u8> {
+        let mut state = self.state.lock().unwrap();
+        state.counter += 1;
+        Sha256::digest(format!("lnd-nwc-fake-{}", state.counter)).to_vec()
+    }
+
+    fn check_failure(&self) -> BackendResult<()> {
+        match &self.config.payment_failure {
+            Some(message) => Err(payment_failed(message)),
+            None => Ok(()),
+        }
+    }
+
+    // Debits the balance, paying an invoice of this node credits it back
+    fn send(&self, payment_hash: &str, preimage: &str, amount_msat: u64) -> BackendResult<Payment> {
+        let mut state = self.state.lock().unwrap();
+        if state.payments.contains_key(payment_hash) {
+            return Err(payment_failed("Invoice is already paid"));
+        }
+        if state.balance_msat < amount_msat.cast_signed() {
+            return Err(payment_failed("Insufficient balance"));
+        }
+
+        let now = Timestamp::now().as_secs();
+        state.balance_msat -= amount_msat.cast_signed();
+        settle(&mut state, payment_hash, amount_msat);
+
+        let payment = Payment {
+            payment_hash: payment_hash.to_string(),
+            preimage: preimage.to_string(),
+            amount_msat,
+            fees_msat: 0,
+            created_at: now,
+            settled_at: Some(now),
+        };
+        state
+            .payments
+            .insert(payment_hash.to_string(), payment.clone());
+        Ok(payment)
+    }
+}
+
+#[async_trait]
+impl WalletBackend for FakeBackend {
+    async fn get_info(&self) -> BackendResult<NodeInfo> {
+        self.simulate_latency().await;
+        Ok(NodeInfo {
+            network: Some(self.config.network.clone()),
+        })
+    }
+
+    async fn balance(&self) -> BackendResult<Balance> {
+        self.simulate_latency().await;
+        let balance_msat = self.state.lock().unwrap().balance_msat;
+        Ok(Balance {
+            channels_msat: balance_msat,
+            spendable_msat: balance_msat,
+            onchain_msat: 0,
+        })
+    }
+
+    async fn decode_invoice(&self, invoice: &str) -> BackendResult<DecodedInvoice> {
+        self.simulate_latency().await;
+        let payment_hash = invoice
+            .strip_prefix(&self.invoice_prefix)
+            .ok_or_else(|| other("Only invoices of the fake backend can be decoded"))?;
+        let state = self.state.lock().unwrap();
+        let invoice = state
+            .invoices
+            .get(payment_hash)
+            .ok_or_else(|| other("Unknown invoice"))?;
+
+        Ok(DecodedInvoice {
+            payment_hash: invoice.payment_hash.clone(),
+            amount_msat: invoice.amount_msat,
+            created_at: invoice.created_at,
+            expiry_secs: invoice
+                .expires_at
+                .map_or(u64::MAX - invoice.created_at, |expires_at| {
+                    expires_at - invoice.created_at
+                }),
+        })
+    }
+
+    // Payments of this node never stay in flight
+    async fn lookup_payment(&self, payment_hash: &str) -> BackendResult<Option<PaymentEntry>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .payments
+            .get(payment_hash)
+            .map(|payment| PaymentEntry {
+                payment: payment.clone(),
+                invoice: None,
+                state: PaymentState::Succeeded,
+            }))
+    }
+
+    async fn pay_invoice(
+        &self,
+        invoice: &str,
+        amount_msat: Option<u64>,
+        _options: &PaymentConfig,
+        _max_fee_msat: Option<u64>,
+    ) -> BackendResult<Payment> {
+        let decoded = self.decode_invoice(invoice).await?;
+        self.check_failure()?;
+
+        let preimage = {
+            let state = self.state.lock().unwrap();
+            state.invoices[&decoded.payment_hash]
+                .preimage
+                .clone()
+                .unwrap_or_default()
+        };
+        let amount_msat = amount_msat.unwrap_or(decoded.amount_msat);
+        self.send(&decoded.payment_hash, &preimage, amount_msat)
+    }
+
+    async fn pay_keysend(
+        &self,
+        _pubkey: &str,
+        amount_msat: u64,
+        preimage: Option<&str>,
+        _tlv_records: &[(u64, String)],
+        _options: &PaymentConfig,
+        _max_fee_msat: Option<u64>,
+    ) -> BackendResult<Payment> {
+        self.simulate_latency().await;
+        self.check_failure()?;
+
+        let preimage = match preimage {
+            Some(preimage) => hex::decode(preimage).map_err(other)?,
+            None => self.next_preimage(),
+        };
+        let payment_hash = hex::encode(Sha256::digest(&preimage));
+        self.send(&payment_hash, &hex::encode(preimage), amount_msat)
+    }
+
+    async fn make_invoice(
+        &self,
+        amount_msat: u64,
+        description: Option<&str>,
+        description_hash: Option<&str>,
+        expiry_secs: Option<u64>,
+    ) -> BackendResult<Invoice> {
+        self.simulate_latency().await;
+
+        let preimage = self.next_preimage();
+        let payment_hash = hex::encode(Sha256::digest(&preimage));
+        let created_at = Timestamp::now().as_secs();
+        let invoice = Invoice {
+            invoice: format!("{}{payment_hash}", self.invoice_prefix),
+            payment_hash: payment_hash.clone(),
+            preimage: Some(hex::encode(preimage)),
+            description: description.map(str::to_string),
+            description_hash: description_hash.map(str::to_string),
+            amount_msat,
+            created_at,
+            expires_at: expiry_secs.map(|secs| created_at + secs),
+            ..Default::default()
+        };
+        self.state
+            .lock()
+            .unwrap()
+            .invoices
+            .insert(payment_hash.clone(), invoice.clone());
+
+        // Simulates a payer settling the invoice
+        if let Some(delay) = self.config.settle_invoices_after_secs {
+            let state = self.state.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(delay)).await;
+                settle(&mut state.lock().unwrap(), &payment_hash, amount_msat);
+            });
+        }
+
+        Ok(invoice)
+    }
+
+    async fn lookup_invoice(
+        &self,
+        payment_hash: Option<&str>,
+        invoice: Option<&str>,
+    ) -> BackendResult<Invoice> {
+        self.simulate_latency().await;
+        let payment_hash = payment_hash
+            .or_else(|| invoice.and_then(|invoice| invoice.strip_prefix(&self.invoice_prefix)))
+            .ok_or_else(|| other("Missing payment hash or payment request"))?;
+
+        let state = self.state.lock().unwrap();
+        state
+            .invoices
+            .get(payment_hash)
+            .cloned()
+            .ok_or_else(|| other("Invoice not found").into())
+    }
+
+    async fn wait_for_invoice_settlement(&self, payment_hash: &str) -> BackendResult<Invoice> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                let invoice = state
+                    .invoices
+                    .get_mut(payment_hash)
+                    .ok_or_else(|| other("Invoice not found"))?;
+                // Unpaid invoices are canceled once expired, like a real node does
+                let now = Timestamp::now().as_secs();
+                if invoice.state == InvoiceState::Open
+                    && invoice
+                        .expires_at
+                        .is_some_and(|expires_at| expires_at <= now)
+                {
+                    invoice.state = InvoiceState::Canceled;
+                }
+                match invoice.state {
+                    InvoiceState::Settled => return Ok(invoice.clone()),
+                    InvoiceState::Canceled => return Err(Box::new(other("Invoice canceled"))),
+                    _ => {}
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(SETTLEMENT_POLL_MILLIS)).await;
+        }
+    }
+}
+
+// Receives the payment of an open invoice made by this node
+fn settle(state: &mut FakeState, payment_hash: &str, amount_msat: u64) {
+    if let Some(invoice) = state.invoices.get_mut(payment_hash)
+        && invoice.state == InvoiceState::Open
+    {
+        invoice.state = InvoiceState::Settled;
+        invoice.amount_paid_msat = amount_msat;
+        invoice.settled_at = Some(Timestamp::now().as_secs());
+        state.balance_msat += amount_msat.cast_signed();
+    }
+}
+
+fn other<E: ToString>(err: E) -> io::Error {
+    io::Error::other(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn expired_invoices_stop_the_settlement_wait() {
+        let backend = FakeBackend::new(&FakeConfig::default());
+        let invoice = backend
+            .make_invoice(1_000, None, None, Some(0))
+            .await
+            .unwrap();
+
+        let wait = backend.wait_for_invoice_settlement(&invoice.payment_hash);
+        let result = tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .expect("still waiting for an expired invoice");
+        assert!(result.is_err());
+
+        let invoice = backend
+            .lookup_invoice(Some(&invoice.payment_hash), None)
+            .await
+            .unwrap();
+        assert_eq!(invoice.state, InvoiceState::Canceled);
+    }
+}
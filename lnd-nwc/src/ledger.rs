@@ -0,0 +1,377 @@
+use rusqlite::{OptionalExtension, Row, TransactionBehavior, params};
+
+use nostr_sdk::Timestamp;
+
+use crate::db::{self, DbResult};
+use crate::nwc_types::{TransactionState, TransactionType};
+
+// Invoices and payments made through each connection, stored in the `transactions` table
+
+#[derive(Debug, Clone)]
+pub struct LedgerEntry {
+    pub id: u64,
+    pub transaction_type: TransactionType,
+    pub state: TransactionState,
+    pub invoice: Option<String>,
+    pub description: Option<String>,
+    pub payment_hash: Option<String>,
+    pub preimage: Option<String>,
+    pub amount_msat: u64,
+    // Fees paid once settled, fees reserved while pending
+    pub fees_msat: u64,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub settled_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TransactionFilter {
+    pub from: Option<u64>,
+    pub until: Option<u64>,
+    pub transaction_type: Option<TransactionType>,
+    pub include_unpaid: bool,
+    pub limit: Option<u64>,
+    pub offset: Option<u64>,
+}
+
+const ENTRY_COLUMNS: &str = "id, transaction_type, state, invoice, description, payment_hash, \
+     preimage, amount_msat, fees_msat, created_at, expires_at, settled_at";
+
+// Settled incoming amounts minus settled and pending outgoing amounts with their fees
+const BALANCE_QUERY: &str = "SELECT COALESCE(SUM(CASE
+        WHEN transaction_type = 'incoming' AND state = 'settled' THEN amount_msat
+        WHEN transaction_type = 'outgoing' AND state IN ('settled', 'pending')
+            THEN -(amount_msat + fees_msat)
+        ELSE 0 END), 0)
+     FROM transactions WHERE connection = ?1";
+
+pub fn balance_msat(name: &str) -> DbResult<i64> {
+    let conn = db::open()?;
+    Ok(conn.query_row(BALANCE_QUERY, params![name], |row| row.get(0))?)
+}
+
+pub fn transactions(name: &str, filter: &TransactionFilter) -> DbResult<Vec<LedgerEntry>> {
+    let conn = db::open()?;
+    let mut statement = conn.prepare(&format!(
+        "SELECT {ENTRY_COLUMNS} FROM transactions
+         WHERE connection = ?1
+           AND (?2 IS NULL OR created_at >= ?2)
+           AND (?3 IS NULL OR created_at <= ?3)
+           AND (?4 IS NULL OR transaction_type = ?4)
+           AND (?5 OR state = 'settled')
+         ORDER BY created_at DESC, id DESC
+         LIMIT ?6 OFFSET ?7"
+    ))?;
+
+    let entries = statement
+        .query_map(
+            params![
+                name,
+                filter.from.map(|t| t as i64),
+                filter.until.map(|t| t as i64),
+                filter.transaction_type.map(type_to_str),
+                filter.include_unpaid,
+                filter.limit.map_or(-1, |limit| limit as i64),
+                filter.offset.unwrap_or(0) as i64,
+            ],
+            entry_from_row,
+        )?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(entries)
+}
+
+pub fn find_by_payment_hash(name: &str, payment_hash: &str) -> DbResult<Option<LedgerEntry>> {
+    let conn = db::open()?;
+    let entry = conn
+        .query_row(
+            &format!(
+                "SELECT {ENTRY_COLUMNS} FROM transactions
+                 WHERE connection = ?1 AND payment_hash = ?2
+                 ORDER BY id DESC LIMIT 1"
+            ),
+            params![name, payment_hash],
+            entry_from_row,
+        )
+        .optional()?;
+    Ok(entry)
+}
+
+pub fn add_invoice(
+    name: &str,
+    invoice: &str,
+    description: Option<String>,
+    payment_hash: &str,
+    amount_msat: u64,
+    expires_at: Option<u64>,
+) -> DbResult<()> {
+    let conn = db::open()?;
+    conn.execute(
+        "INSERT INTO transactions
+            (connection, transaction_type, state, invoice, description, payment_hash,
+             amount_msat, created_at, expires_at)
+         VALUES (?1, 'incoming', 'pending', ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            name,
+            invoice,
+            description,
+            payment_hash,
+            amount_msat as i64,
+            Timestamp::now().as_secs() as i64,
+            expires_at.map(|t| t as i64),
+        ],
+    )?;
+    Ok(())
+}
+
+// Credits the connection once, further calls for the same invoice are ignored
+pub fn settle_invoice(
+    name: &str,
+    payment_hash: &str,
+    amount_paid_msat: u64,
+    preimage: Option<String>,
+    settled_at: u64,
+) -> DbResult<()> {
+    let conn = db::open()?;
+    conn.execute(
+        "UPDATE transactions
+         SET state = 'settled', amount_msat = ?3, preimage = ?4, settled_at = ?5
+         WHERE connection = ?1 AND payment_hash = ?2
+           AND transaction_type = 'incoming' AND state != 'settled'",
+        params![
+            name,
+            payment_hash,
+            amount_paid_msat as i64,
+            preimage,
+            settled_at as i64
+        ],
+    )?;
+    Ok(())
+}
+
+// Records an outgoing payment before it is sent. Its hash, when known upfront, lets
+// a payment whose outcome is unknown be reconciled with the node later.
+pub fn start_payment(
+    name: &str,
+    invoice: Option<String>,
+    payment_hash: Option<&str>,
+    amount_msat: u64,
+) -> DbResult<u64> {
+    let conn = db::open()?;
+    insert_payment(&conn, name, invoice, payment_hash, amount_msat, 0)
+}
+
+// Holds `amount_msat` plus a fee reserve on the connection's balance before paying.
+// Returns the entry id and the fee reserve, or the available balance when insufficient.
+pub fn reserve_payment(
+    name: &str,
+    invoice: Option<String>,
+    payment_hash: Option<&str>,
+    amount_msat: u64,
+    max_fee_msat: u64,
+) -> DbResult<Result<(u64, u64), i64>> {
+    let mut conn = db::open()?;
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+    let available: i64 = tx.query_row(BALANCE_QUERY, params![name], |row| row.get(0))?;
+    let amount = amount_msat.cast_signed();
+    if available < amount {
+        return Ok(Err(available));
+    }
+
+    let fee_reserve = max_fee_msat.min((available - amount).cast_unsigned());
+    let id = insert_payment(&tx, name, invoice, payment_hash, amount_msat, fee_reserve)?;
+    tx.commit()?;
+    Ok(Ok((id, fee_reserve)))
+}
+
+pub fn complete_payment(
+    id: u64,
+    payment_hash: &str,
+    preimage: &str,
+    fees_msat: u64,
+) -> DbResult<()> {
+    let conn = db::open()?;
+    conn.execute(
+        "UPDATE transactions
+         SET state = 'settled', payment_hash = ?2, preimage = ?3, fees_msat = ?4, settled_at = ?5
+         WHERE id = ?1",
+        params![
+            id as i64,
+            payment_hash,
+            preimage,
+            fees_msat as i64,
+            Timestamp::now().as_secs() as i64
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn cancel_payment(id: u64) -> DbResult<()> {
+    let conn = db::open()?;
+    conn.execute(
+        "UPDATE transactions SET state = 'failed', fees_msat = 0 WHERE id = ?1",
+        params![id as i64],
+    )?;
+    Ok(())
+}
+
+// Outgoing payments still waiting for their outcome, oldest first
+pub fn pending_payments(name: &str) -> DbResult<Vec<LedgerEntry>> {
+    let conn = db::open()?;
+    let mut statement = conn.prepare(&format!(
+        "SELECT {ENTRY_COLUMNS} FROM transactions
+         WHERE connection = ?1 AND transaction_type = 'outgoing' AND state = 'pending'
+         ORDER BY id"
+    ))?;
+    let entries = statement
+        .query_map(params![name], entry_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(entries)
+}
+
+fn insert_payment(
+    conn: &rusqlite::Connection,
+    name: &str,
+    invoice: Option<String>,
+    payment_hash: Option<&str>,
+    amount_msat: u64,
+    fee_reserve_msat: u64,
+) -> DbResult<u64> {
+    conn.execute(
+        "INSERT INTO transactions
+            (connection, transaction_type, state, invoice, payment_hash, amount_msat, fees_msat,
+             created_at)
+         VALUES (?1, 'outgoing', 'pending', ?2, ?3, ?4, ?5, ?6)",
+        params![
+            name,
+            invoice,
+            payment_hash,
+            amount_msat as i64,
+            fee_reserve_msat as i64,
+            Timestamp::now().as_secs() as i64
+        ],
+    )?;
+    Ok(conn.last_insert_rowid() as u64)
+}
+
+fn entry_from_row(row: &Row) -> rusqlite::Result<LedgerEntry> {
+    Ok(LedgerEntry {
+        id: row.get::<_, i64>(0)? as u64,
+        transaction_type: type_from_str(&row.get::<_, String>(1)?),
+        state: state_from_str(&row.get::<_, String>(2)?),
+        invoice: row.get(3)?,
+        description: row.get(4)?,
+        payment_hash: row.get(5)?,
+        preimage: row.get(6)?,
+        amount_msat: row.get::<_, i64>(7)? as u64,
+        fees_msat: row.get::<_, i64>(8)? as u64,
+        created_at: row.get::<_, i64>(9)? as u64,
+        expires_at: row.get::<_, Option<i64>>(10)?.map(|t| t as u64),
+        settled_at: row.get::<_, Option<i64>>(11)?.map(|t| t as u64),
+    })
+}
+
+fn type_to_str(transaction_type: TransactionType) -> &'static str {
+    match transaction_type {
+        TransactionType::Incoming => "incoming",
+        TransactionType::Outgoing => "outgoing",
+    }
+}
+
+fn type_from_str(value: &str) -> TransactionType {
+    match value {
+        "incoming" => TransactionType::Incoming,
+        _ => TransactionType::Outgoing,
+    }
+}
+
+fn state_from_str(value: &str) -> TransactionState {
+    match value {
+        "settled" => TransactionState::Settled,
+        "failed" => TransactionState::Failed,
+        _ => TransactionState::Pending,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::use_test_config_dir;
+
+    // Tests share the database, each one uses its own connection names
+    fn funded(name: &str, amount_msat: u64) {
+        use_test_config_dir();
+        add_invoice(name, "lnbcrt1funding", None, name, amount_msat, None).unwrap();
+        settle_invoice(name, name, amount_msat, None, 0).unwrap();
+    }
+
+    fn payment(name: &str, id: u64) -> LedgerEntry {
+        let filter = TransactionFilter {
+            include_unpaid: true,
+            ..Default::default()
+        };
+        transactions(name, &filter)
+            .unwrap()
+            .into_iter()
+            .find(|entry| entry.id == id)
+            .unwrap()
+    }
+
+    #[test]
+    fn reservations_are_limited_to_the_balance() {
+        funded("ledger-limited", 1_000);
+
+        let reserved = reserve_payment("ledger-limited", None, None, 1_001, 0).unwrap();
+        assert_eq!(reserved, Err(1_000));
+        assert_eq!(balance_msat("ledger-limited").unwrap(), 1_000);
+    }
+
+    #[test]
+    fn fee_reserve_is_capped_by_the_remaining_balance() {
+        funded("ledger-fee-reserve", 1_000);
+
+        let (_, fee_reserve) = reserve_payment("ledger-fee-reserve", None, None, 500, 10)
+            .unwrap()
+            .unwrap();
+        assert_eq!(fee_reserve, 10);
+        assert_eq!(balance_msat("ledger-fee-reserve").unwrap(), 490);
+
+        let (_, fee_reserve) = reserve_payment("ledger-fee-reserve", None, None, 400, 500)
+            .unwrap()
+            .unwrap();
+        assert_eq!(fee_reserve, 90);
+        assert_eq!(balance_msat("ledger-fee-reserve").unwrap(), 0);
+    }
+
+    #[test]
+    fn canceled_payments_release_their_hold() {
+        funded("ledger-canceled", 1_000);
+
+        let (id, _) = reserve_payment("ledger-canceled", None, None, 600, 50)
+            .unwrap()
+            .unwrap();
+        assert_eq!(balance_msat("ledger-canceled").unwrap(), 350);
+
+        cancel_payment(id).unwrap();
+        assert_eq!(balance_msat("ledger-canceled").unwrap(), 1_000);
+        let entry = payment("ledger-canceled", id);
+        assert_eq!(entry.state, TransactionState::Failed);
+        assert_eq!(entry.fees_msat, 0);
+    }
+
+    #[test]
+    fn completed_payments_charge_the_actual_fee() {
+        funded("ledger-completed", 1_000);
+
+        let (id, _) = reserve_payment("ledger-completed", None, None, 600, 50)
+            .unwrap()
+            .unwrap();
+        complete_payment(id, "ledger-completed-hash", "preimage", 7).unwrap();
+
+        assert_eq!(balance_msat("ledger-completed").unwrap(), 393);
+        let entry = payment("ledger-completed", id);
+        assert_eq!(entry.state, TransactionState::Settled);
+        assert_eq!(entry.fees_msat, 7);
+        assert_eq!(entry.preimage.as_deref(), Some("preimage"));
+    }
+}
@@ -1,78 +1,471 @@
-use lnd_grpc_rust;
+use async_trait::async_trait;
+use lnd_grpc_rust::LndClient;
 use lnd_grpc_rust::invoicesrpc::lookup_invoice_msg::InvoiceRef;
 use lnd_grpc_rust::invoicesrpc::{LookupInvoiceMsg, SubscribeSingleInvoiceRequest};
+use lnd_grpc_rust::lnrpc::channel_point::FundingTxid;
+use lnd_grpc_rust::lnrpc::close_status_update::Update as CloseUpdate;
 use lnd_grpc_rust::lnrpc::{self, invoice::InvoiceState, payment::PaymentStatus};
 use lnd_grpc_rust::routerrpc;
+use nostr_sdk::serde_json::{self, Value};
 use secp256k1::rand::{RngCore, rngs::OsRng};
+use serde::Serialize;
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::fs;
 use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::OnceCell;
 
-use crate::config::load_config;
+use crate::backend::{self, BackendResult, WalletBackend, default_fee_limit_msat};
+use crate::config::{Config, LndConfig, LndTransport, PaymentConfig, load_config};
+use crate::lnd_rest;
+use crate::nostr;
+use crate::output::OutputFormat;
 
 const KEYSEND_PREIMAGE_TYPE: u64 = 5_482_373_484;
+pub(crate) const DEFAULT_PAYMENT_TIMEOUT_SECS: i32 = 60;
+pub(crate) const ALREADY_CONNECTED: &str = "already connected";
+pub(crate) const PAYMENT_NOT_INITIATED: &str = "payment isn't initiated";
+pub(crate) const PAYMENT_LOOKUP_TIMEOUT_SECS: u64 = 10;
 type LndResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+// TLV records of a payment by type
+pub(crate) type CustomRecords = HashMap<u64, Vec<u8>>;
+
+#[derive(Serialize)]
+struct NodeInfo {
+    identity_pubkey: String,
+    alias: String,
+    version: String,
+    block_height: u32,
+    synced_to_chain: bool,
+    synced_to_graph: bool,
+    num_active_channels: u32,
+    num_inactive_channels: u32,
+    num_pending_channels: u32,
+    num_peers: u32,
+    chains: Vec<String>,
+    uris: Vec<String>,
+}
+
+fn node_config(cfg: &Config, node: Option<&str>) -> LndResult<LndConfig> {
+    cfg.lnd_node(node).cloned().ok_or_else(|| {
+        map_to_other(format!(
+            "LND node {} is not configured",
+            node.unwrap_or_default()
+        ))
+        .into()
+    })
+}
+
+fn node_backend(node: Option<&str>) -> LndResult<Arc<dyn WalletBackend>> {
+    Ok(backend::lnd_backend(&node_config(&load_config(), node)?))
+}
+
+pub async fn display_info(node: Option<&str>, output: OutputFormat) -> LndResult<()> {
+    let lnd = &node_config(&load_config(), node)?;
+    let info = match lnd.transport {
+        LndTransport::Grpc => node_info_from_grpc(get_info(&mut connect(lnd).await?).await?),
+        LndTransport::Rest => node_info_from_rest(&lnd_rest::get_info(lnd).await?),
+    };
+
+    if output.is_json() {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
+
+    println!("Node {} ({})", info.alias, info.identity_pubkey);
+    println!("\tVersion: {}", info.version);
+    println!("\tChains: {}", info.chains.join(", "));
+    println!(
+        "\tBlock height: {} (synced to chain: {}, synced to graph: {})",
+        info.block_height, info.synced_to_chain, info.synced_to_graph
+    );
+    println!(
+        "\tChannels: {} active, {} inactive, {} pending",
+        info.num_active_channels, info.num_inactive_channels, info.num_pending_channels
+    );
+    println!("\tPeers: {}", info.num_peers);
+    for uri in &info.uris {
+        println!("\tURI: {uri}");
+    }
+
+    Ok(())
+}
+
+pub async fn display_balance(node: Option<&str>, output: OutputFormat) -> LndResult<()> {
+    let balance = node_backend(node)?.balance().await?;
+
+    if output.is_json() {
+        println!("{}", serde_json::to_string_pretty(&balance)?);
+        return Ok(());
+    }
+
+    println!("Balance:");
+    println!("\tChannels: {} msat", balance.channels_msat);
+    println!("\tSpendable: {} msat", balance.spendable_msat);
+    println!("\tOn-chain: {} msat", balance.onchain_msat);
+
+    Ok(())
+}
+
+pub async fn display_channels(node: Option<&str>, output: OutputFormat) -> LndResult<()> {
+    let channels = node_backend(node)?.list_channels().await?;
+
+    if output.is_json() {
+        let channels = serde_json::json!({ "channels": channels });
+        println!("{}", serde_json::to_string_pretty(&channels)?);
+        return Ok(());
+    }
+
+    if channels.is_empty() {
+        println!("No channels");
+        return Ok(());
+    }
+    println!(
+        "{:<20} {:<66} {:>12} {:>12} {:>12}  STATE",
+        "CHANNEL ID", "REMOTE PUBKEY", "CAPACITY", "LOCAL", "REMOTE"
+    );
+    for channel in &channels {
+        println!(
+            "{:<20} {:<66} {:>12} {:>12} {:>12}  {}{}",
+            channel.channel_id,
+            channel.remote_pubkey,
+            channel.capacity_sat,
+            channel.local_balance_sat,
+            channel.remote_balance_sat,
+            if channel.active { "active" } else { "inactive" },
+            if channel.private { ", private" } else { "" }
+        );
+    }
+    println!("Amounts in sat");
+
+    Ok(())
+}
+
+pub async fn display_invoices(
+    node: Option<&str>,
+    limit: u64,
+    output: OutputFormat,
+) -> LndResult<()> {
+    let invoices = node_backend(node)?.list_invoices(limit).await?;
+
+    if output.is_json() {
+        let invoices = invoices
+            .iter()
+            .map(nostr::invoice_to_lookup_result)
+            .collect::<Vec<_>>();
+        let invoices = serde_json::json!({ "invoices": invoices });
+        println!("{}", serde_json::to_string_pretty(&invoices)?);
+        return Ok(());
+    }
+
+    if invoices.is_empty() {
+        println!("No invoices");
+        return Ok(());
+    }
+    println!(
+        "{:<20}  {:<8} {:>14}  {:<64}  DESCRIPTION",
+        "CREATED", "STATE", "AMOUNT", "PAYMENT HASH"
+    );
+    for invoice in &invoices {
+        println!(
+            "{:<20}  {:<8} {:>14}  {:<64}  {}",
+            nostr_sdk::Timestamp::from(invoice.created_at).to_human_datetime(),
+            format!("{:?}", invoice.state).to_lowercase(),
+            invoice.received_msat(),
+            invoice.payment_hash,
+            invoice.description.as_deref().unwrap_or_default()
+        );
+    }
+    println!("Amounts in msat");
+
+    Ok(())
+}
+
+pub async fn display_payments(
+    node: Option<&str>,
+    limit: u64,
+    output: OutputFormat,
+) -> LndResult<()> {
+    let payments = node_backend(node)?.list_payments(limit).await?;
+
+    if output.is_json() {
+        let payments = payments
+            .iter()
+            .map(nostr::payment_to_lookup_result)
+            .collect::<Vec<_>>();
+        let payments = serde_json::json!({ "payments": payments });
+        println!("{}", serde_json::to_string_pretty(&payments)?);
+        return Ok(());
+    }
+
+    if payments.is_empty() {
+        println!("No payments");
+        return Ok(());
+    }
+    println!(
+        "{:<20}  {:<9} {:>14} {:>10}  PAYMENT HASH",
+        "CREATED", "STATE", "AMOUNT", "FEES"
+    );
+    for entry in &payments {
+        println!(
+            "{:<20}  {:<9} {:>14} {:>10}  {}",
+            nostr_sdk::Timestamp::from(entry.payment.created_at).to_human_datetime(),
+            format!("{:?}", entry.state).to_lowercase(),
+            entry.payment.amount_msat,
+            entry.payment.fees_msat,
+            entry.payment.payment_hash
+        );
+    }
+    println!("Amounts in msat");
 
-pub async fn display_info() {
-    let info = get_info().await;
-    tracing::info!("{:?}", info);
+    Ok(())
+}
+
+fn node_info_from_grpc(info: lnrpc::GetInfoResponse) -> NodeInfo {
+    NodeInfo {
+        identity_pubkey: info.identity_pubkey,
+        alias: info.alias,
+        version: info.version,
+        block_height: info.block_height,
+        synced_to_chain: info.synced_to_chain,
+        synced_to_graph: info.synced_to_graph,
+        num_active_channels: info.num_active_channels,
+        num_inactive_channels: info.num_inactive_channels,
+        num_pending_channels: info.num_pending_channels,
+        num_peers: info.num_peers,
+        chains: info
+            .chains
+            .iter()
+            .map(|chain| format!("bitcoin/{}", chain.network))
+            .collect(),
+        uris: info.uris,
+    }
 }
 
-async fn get_info() -> LndResult<lnd_grpc_rust::lnrpc::GetInfoResponse> {
-    let mut client = connect_to_lnd().await?;
+// Same fields as the gRPC response, the proxy leaves out zero values
+fn node_info_from_rest(info: &Value) -> NodeInfo {
+    let text = |value: &Value| value.as_str().unwrap_or_default().to_string();
+    let count = |value: &Value| value.as_u64().unwrap_or(0) as u32;
+    let list = |value: &Value| value.as_array().cloned().unwrap_or_default();
+
+    NodeInfo {
+        identity_pubkey: text(&info["identity_pubkey"]),
+        alias: text(&info["alias"]),
+        version: text(&info["version"]),
+        block_height: count(&info["block_height"]),
+        synced_to_chain: info["synced_to_chain"].as_bool().unwrap_or(false),
+        synced_to_graph: info["synced_to_graph"].as_bool().unwrap_or(false),
+        num_active_channels: count(&info["num_active_channels"]),
+        num_inactive_channels: count(&info["num_inactive_channels"]),
+        num_pending_channels: count(&info["num_pending_channels"]),
+        num_peers: count(&info["num_peers"]),
+        chains: list(&info["chains"])
+            .iter()
+            .map(|chain| format!("{}/{}", text(&chain["chain"]), text(&chain["network"])))
+            .collect(),
+        uris: list(&info["uris"]).iter().map(text).collect(),
+    }
+}
 
+pub(crate) async fn get_info(
+    client: &mut LndClient,
+) -> LndResult<lnd_grpc_rust::lnrpc::GetInfoResponse> {
     let info = client
         .lightning()
         .get_info(lnd_grpc_rust::lnrpc::GetInfoRequest {})
-        .await
-        .expect("failed to get info")
+        .await?
         .into_inner();
 
     Ok(info)
 }
 
-pub(crate) async fn channel_balance() -> LndResult<lnd_grpc_rust::lnrpc::ChannelBalanceResponse> {
-    let mut client = connect_to_lnd().await?;
+pub(crate) async fn decode_invoice(
+    client: &mut LndClient,
+    invoice: &str,
+) -> LndResult<lnrpc::PayReq> {
+    let pay_req = client
+        .lightning()
+        .decode_pay_req(lnrpc::PayReqString {
+            pay_req: invoice.to_string(),
+        })
+        .await?
+        .into_inner();
+
+    Ok(pay_req)
+}
+
+// First update of the payment, its current state. No update when LND never attempted it.
+pub(crate) async fn track_payment(
+    client: &mut LndClient,
+    payment_hash_hex: &str,
+) -> LndResult<Option<lnrpc::Payment>> {
+    let payment_hash = hex::decode(payment_hash_hex).map_err(map_to_other)?;
+
+    let track = async {
+        let mut stream = client
+            .router()
+            .track_payment_v2(routerrpc::TrackPaymentRequest {
+                payment_hash,
+                no_inflight_updates: false,
+            })
+            .await?
+            .into_inner();
+        stream.message().await
+    };
+    let payment = tokio::time::timeout(Duration::from_secs(PAYMENT_LOOKUP_TIMEOUT_SECS), track)
+        .await
+        .map_err(|_| map_to_other("Timed out looking up the payment"))?;
+    match payment {
+        Ok(payment) => Ok(payment),
+        Err(status) if status.message().contains(PAYMENT_NOT_INITIATED) => Ok(None),
+        Err(status) => Err(Box::new(status)),
+    }
+}
 
+pub(crate) async fn channel_balance(
+    client: &mut LndClient,
+) -> LndResult<lnd_grpc_rust::lnrpc::ChannelBalanceResponse> {
     let info = client
         .lightning()
         .channel_balance(lnd_grpc_rust::lnrpc::ChannelBalanceRequest {})
-        .await
-        .expect("failed to get balance")
+        .await?
         .into_inner();
 
     Ok(info)
 }
 
+pub(crate) async fn wallet_balance(
+    client: &mut LndClient,
+) -> LndResult<lnrpc::WalletBalanceResponse> {
+    let balance = client
+        .lightning()
+        .wallet_balance(lnrpc::WalletBalanceRequest::default())
+        .await?
+        .into_inner();
+
+    Ok(balance)
+}
+
+pub(crate) async fn list_channels(
+    client: &mut LndClient,
+    active_only: bool,
+) -> LndResult<Vec<lnrpc::Channel>> {
+    let response = client
+        .lightning()
+        .list_channels(lnrpc::ListChannelsRequest {
+            active_only,
+            ..Default::default()
+        })
+        .await?
+        .into_inner();
+
+    Ok(response.channels)
+}
+
+// The latest `limit` invoices, oldest first
+pub(crate) async fn list_invoices(
+    client: &mut LndClient,
+    limit: u64,
+) -> LndResult<Vec<lnrpc::Invoice>> {
+    let response = client
+        .lightning()
+        .list_invoices(lnrpc::ListInvoiceRequest {
+            num_max_invoices: limit,
+            reversed: true,
+            ..Default::default()
+        })
+        .await?
+        .into_inner();
+
+    Ok(response.invoices)
+}
+
+// The latest `limit` payments including the unfinished ones, oldest first
+pub(crate) async fn list_payments(
+    client: &mut LndClient,
+    limit: u64,
+) -> LndResult<Vec<lnrpc::Payment>> {
+    let response = client
+        .lightning()
+        .list_payments(lnrpc::ListPaymentsRequest {
+            include_incomplete: true,
+            max_payments: limit,
+            reversed: true,
+            ..Default::default()
+        })
+        .await?
+        .into_inner();
+
+    Ok(response.payments)
+}
+
 pub(crate) async fn pay_invoice(
+    client: &mut LndClient,
     invoice: &str,
     amount_msat: Option<u64>,
+    options: &PaymentConfig,
+    max_fee_msat: Option<u64>,
 ) -> LndResult<lnrpc::Payment> {
-    let request = create_payment_request(invoice, amount_msat, default_fee_limit_msat(amount_msat));
-    execute_payment(request).await
+    let request = create_payment_request(
+        invoice,
+        amount_msat,
+        fee_limit_msat(amount_msat, max_fee_msat),
+        options,
+    )?;
+    execute_payment(client, request).await
 }
 
 fn create_payment_request(
     invoice: &str,
     amount_msat: Option<u64>,
     fee_limit_msat: i64,
-) -> routerrpc::SendPaymentRequest {
-    routerrpc::SendPaymentRequest {
+    options: &PaymentConfig,
+) -> LndResult<routerrpc::SendPaymentRequest> {
+    let mut request = routerrpc::SendPaymentRequest {
         payment_request: invoice.to_string(),
         amt_msat: amount_msat
             .map(|value| i64::try_from(value).unwrap_or(i64::MAX))
             .unwrap_or(0),
-        fee_limit_msat: fee_limit_msat,
+        fee_limit_msat,
         ..Default::default()
+    };
+    apply_payment_options(&mut request, options)?;
+    Ok(request)
+}
+
+fn apply_payment_options(
+    request: &mut routerrpc::SendPaymentRequest,
+    options: &PaymentConfig,
+) -> LndResult<()> {
+    request.timeout_seconds = options
+        .timeout_seconds
+        .map(|secs| i32::try_from(secs).unwrap_or(i32::MAX))
+        .unwrap_or(DEFAULT_PAYMENT_TIMEOUT_SECS);
+    request.max_parts = options.max_parts.unwrap_or_default();
+    request.outgoing_chan_ids = options.outgoing_chan_ids.clone();
+    request.allow_self_payment = options.allow_self_payment;
+
+    if let Some(last_hop) = &options.last_hop_pubkey {
+        let last_hop_pubkey = hex::decode(last_hop).map_err(map_to_other)?;
+        if last_hop_pubkey.len() != 33 {
+            return Err(Box::new(map_to_other("Last hop pubkey must be 33 bytes")));
+        }
+        request.last_hop_pubkey = last_hop_pubkey;
     }
+
+    Ok(())
 }
 
 pub(crate) async fn pay_keysend(
+    client: &mut LndClient,
     pubkey: &str,
     amount_msat: u64,
     preimage: Option<&str>,
     tlv_records: &[(u64, String)],
+    options: &PaymentConfig,
+    max_fee_msat: Option<u64>,
 ) -> LndResult<lnrpc::Payment> {
     let dest = hex::decode(pubkey).map_err(map_to_other)?;
     if dest.len() != 33 {
@@ -81,6 +474,27 @@ pub(crate) async fn pay_keysend(
         )));
     }
 
+    let (payment_preimage, dest_custom_records) = keysend_records(preimage, tlv_records)?;
+    let payment_hash = Sha256::digest(&payment_preimage).to_vec();
+
+    let mut request = routerrpc::SendPaymentRequest {
+        dest,
+        amt_msat: i64::try_from(amount_msat).unwrap_or(i64::MAX),
+        payment_hash,
+        dest_custom_records,
+        fee_limit_msat: fee_limit_msat(Some(amount_msat), max_fee_msat),
+        ..Default::default()
+    };
+    apply_payment_options(&mut request, options)?;
+
+    execute_payment(client, request).await
+}
+
+// Preimage of a keysend payment and the custom records carrying it
+pub(crate) fn keysend_records(
+    preimage: Option<&str>,
+    tlv_records: &[(u64, String)],
+) -> LndResult<(Vec<u8>, CustomRecords)> {
     let payment_preimage = match preimage {
         Some(raw) => hex::decode(raw).map_err(map_to_other)?,
         None => {
@@ -94,8 +508,6 @@ pub(crate) async fn pay_keysend(
         return Err(Box::new(map_to_other("Keysend preimage must be 32 bytes")));
     }
 
-    let payment_hash = Sha256::digest(&payment_preimage).to_vec();
-
     let mut dest_custom_records: HashMap<u64, Vec<u8>> = HashMap::new();
     dest_custom_records.insert(KEYSEND_PREIMAGE_TYPE, payment_preimage.clone());
     for (typ, value) in tlv_records {
@@ -107,31 +519,23 @@ pub(crate) async fn pay_keysend(
         dest_custom_records.insert(*typ, value_bytes);
     }
 
-    let request = routerrpc::SendPaymentRequest {
-        dest,
-        amt_msat: i64::try_from(amount_msat).unwrap_or(i64::MAX),
-        payment_hash,
-        dest_custom_records,
-        fee_limit_msat: default_fee_limit_msat(Some(amount_msat)),
-        timeout_seconds: 60,
-        ..Default::default()
-    };
-
-    execute_payment(request).await
+    Ok((payment_preimage, dest_custom_records))
 }
 
-async fn execute_payment(request: routerrpc::SendPaymentRequest) -> LndResult<lnrpc::Payment> {
-    let mut client = connect_to_lnd().await?;
+async fn execute_payment(
+    client: &mut LndClient,
+    request: routerrpc::SendPaymentRequest,
+) -> LndResult<lnrpc::Payment> {
     let mut stream = client.router().send_payment_v2(request).await?.into_inner();
 
     while let Some(payment) = stream.message().await? {
-        match PaymentStatus::from_i32(payment.status) {
+        match PaymentStatus::try_from(payment.status).ok() {
             Some(PaymentStatus::Succeeded) => return Ok(payment),
             Some(PaymentStatus::Failed) => {
-                return Err(Box::new(map_to_other(format!(
+                return Err(backend::payment_failed(format!(
                     "Payment failed with reason {:?}",
                     payment.failure_reason
-                ))));
+                )));
             }
             _ => continue,
         }
@@ -141,12 +545,12 @@ async fn execute_payment(request: routerrpc::SendPaymentRequest) -> LndResult<ln
 }
 
 pub(crate) async fn make_invoice(
+    client: &mut LndClient,
     amount_msat: u64,
     description: Option<&str>,
     description_hash: Option<&str>,
     expiry_secs: Option<u64>,
 ) -> LndResult<lnrpc::AddInvoiceResponse> {
-    let mut client = connect_to_lnd().await?;
     let description_hash_bytes = match description_hash {
         Some(hash) if !hash.is_empty() => Some(hex::decode(hash).map_err(map_to_other)?),
         _ => None,
@@ -167,11 +571,10 @@ pub(crate) async fn make_invoice(
 }
 
 pub(crate) async fn lookup_invoice(
+    client: &mut LndClient,
     payment_hash_hex: Option<&str>,
     payment_request: Option<&str>,
 ) -> LndResult<lnrpc::Invoice> {
-    let mut client = connect_to_lnd().await?;
-
     let payment_hash = if let Some(hash_hex) = payment_hash_hex {
         hex::decode(hash_hex).map_err(map_to_other)?
     } else if let Some(pay_req) = payment_request {
@@ -204,9 +607,9 @@ pub(crate) async fn lookup_invoice(
 }
 
 pub(crate) async fn wait_for_invoice_settlement(
+    client: &mut LndClient,
     payment_hash: Vec<u8>,
 ) -> LndResult<lnrpc::Invoice> {
-    let mut client = connect_to_lnd().await?;
     let mut stream = client
         .invoices()
         .subscribe_single_invoice(SubscribeSingleInvoiceRequest {
@@ -216,7 +619,7 @@ pub(crate) async fn wait_for_invoice_settlement(
         .into_inner();
 
     while let Some(invoice) = stream.message().await? {
-        match InvoiceState::from_i32(invoice.state) {
+        match InvoiceState::try_from(invoice.state).ok() {
             Some(InvoiceState::Settled) => return Ok(invoice),
             Some(InvoiceState::Canceled) => return Err(Box::new(map_to_other("Invoice canceled"))),
             _ => continue,
@@ -228,29 +631,466 @@ pub(crate) async fn wait_for_invoice_settlement(
     )))
 }
 
-fn default_fee_limit_msat(amount_msat: Option<u64>) -> i64 {
-    match amount_msat {
-        Some(amount) if amount > 0 => {
-            let limit = if amount <= 1_000_000 {
-                amount
-            } else {
-                amount / 20 // ~5%
-            };
-            i64::try_from(limit).unwrap_or(i64::MAX)
+// `host` is `host:port`, a peer that is already connected is not an error
+pub(crate) async fn connect_peer(
+    client: &mut LndClient,
+    pubkey: &str,
+    host: &str,
+) -> LndResult<()> {
+    let result = client
+        .lightning()
+        .connect_peer(lnrpc::ConnectPeerRequest {
+            addr: Some(lnrpc::LightningAddress {
+                pubkey: pubkey.to_string(),
+                host: host.to_string(),
+            }),
+            ..Default::default()
+        })
+        .await;
+    match result {
+        Err(status) if !status.message().contains(ALREADY_CONNECTED) => Err(Box::new(status)),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) async fn open_channel(
+    client: &mut LndClient,
+    pubkey: &str,
+    amount_sat: u64,
+    push_sat: u64,
+    private: bool,
+) -> LndResult<lnrpc::ChannelPoint> {
+    let channel_point = client
+        .lightning()
+        .open_channel_sync(lnrpc::OpenChannelRequest {
+            node_pubkey: hex::decode(pubkey).map_err(map_to_other)?,
+            local_funding_amount: i64::try_from(amount_sat).map_err(map_to_other)?,
+            push_sat: i64::try_from(push_sat).map_err(map_to_other)?,
+            private,
+            ..Default::default()
+        })
+        .await?
+        .into_inner();
+
+    Ok(channel_point)
+}
+
+// Resolves once the closing transaction is broadcast, with its txid bytes
+pub(crate) async fn close_channel(
+    client: &mut LndClient,
+    channel_point: &str,
+    force: bool,
+) -> LndResult<Vec<u8>> {
+    let (funding_txid, output_index) = parse_channel_point(channel_point)?;
+    let mut stream = client
+        .lightning()
+        .close_channel(lnrpc::CloseChannelRequest {
+            channel_point: Some(lnrpc::ChannelPoint {
+                funding_txid: Some(FundingTxid::FundingTxidStr(funding_txid)),
+                output_index,
+            }),
+            force,
+            ..Default::default()
+        })
+        .await?
+        .into_inner();
+
+    while let Some(update) = stream.message().await? {
+        match update.update {
+            Some(CloseUpdate::ClosePending(pending)) => return Ok(pending.txid),
+            Some(CloseUpdate::ChanClose(close)) => return Ok(close.closing_txid),
+            _ => continue,
+        }
+    }
+
+    Err(Box::new(map_to_other(
+        "Close stream ended before the closing transaction",
+    )))
+}
+
+// `funding_txid:output_index`, as listed by LND
+pub(crate) fn parse_channel_point(channel_point: &str) -> LndResult<(String, u32)> {
+    channel_point
+        .split_once(':')
+        .filter(|(txid, _)| txid.len() == 64 && hex::decode(txid).is_ok())
+        .and_then(|(txid, index)| Some((txid.to_string(), index.parse().ok()?)))
+        .ok_or_else(|| map_to_other(format!("Invalid channel point {channel_point}")).into())
+}
+
+// Txid bytes are in the internal order, the reverse of the displayed one
+pub(crate) fn txid_to_hex(txid: &[u8]) -> String {
+    txid.iter()
+        .rev()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+pub(crate) fn fee_limit_msat(amount_msat: Option<u64>, max_fee_msat: Option<u64>) -> i64 {
+    let limit = default_fee_limit_msat(amount_msat);
+    match max_fee_msat {
+        Some(max_fee) => limit.min(i64::try_from(max_fee).unwrap_or(i64::MAX)),
+        None => limit,
+    }
+}
+
+// `WalletBackend` over the LND gRPC API
+pub struct LndBackend {
+    config: LndConfig,
+    // Connected on first use and shared by the requests, its connections are pooled
+    client: OnceCell<LndClient>,
+}
+
+impl LndBackend {
+    pub fn new(config: &LndConfig) -> Self {
+        LndBackend {
+            config: config.clone(),
+            client: OnceCell::new(),
         }
-        _ => i64::MAX,
+    }
+
+    async fn client(&self) -> LndResult<LndClient> {
+        let client = self
+            .client
+            .get_or_try_init(|| connect(&self.config))
+            .await?;
+        Ok(client.clone())
     }
 }
 
-async fn connect_to_lnd() -> LndResult<lnd_grpc_rust::LndClient> {
-    let cfg = load_config();
+#[async_trait]
+impl WalletBackend for LndBackend {
+    async fn get_info(&self) -> BackendResult<backend::NodeInfo> {
+        let info = get_info(&mut self.client().await?).await?;
+        Ok(backend::NodeInfo {
+            network: info.chains.first().map(|chain| chain.network.clone()),
+        })
+    }
+
+    async fn balance(&self) -> BackendResult<backend::Balance> {
+        let channels_msat = channel_balance(&mut self.client().await?)
+            .await?
+            .local_balance
+            .map_or(0, |balance| balance.msat.cast_signed());
+        let spendable_sat = list_channels(&mut self.client().await?, true)
+            .await?
+            .iter()
+            .map(|channel| {
+                let reserve = channel
+                    .local_constraints
+                    .as_ref()
+                    .map_or(0, |constraints| constraints.chan_reserve_sat.cast_signed());
+                (channel.local_balance - reserve).max(0)
+            })
+            .sum::<i64>();
+        let onchain_sat = wallet_balance(&mut self.client().await?)
+            .await?
+            .confirmed_balance;
+
+        Ok(backend::Balance {
+            channels_msat,
+            spendable_msat: spendable_sat.saturating_mul(1000),
+            onchain_msat: onchain_sat.saturating_mul(1000),
+        })
+    }
+
+    async fn decode_invoice(&self, invoice: &str) -> BackendResult<backend::DecodedInvoice> {
+        let pay_req = decode_invoice(&mut self.client().await?, invoice).await?;
+        Ok(backend::DecodedInvoice {
+            payment_hash: pay_req.payment_hash,
+            amount_msat: u64::try_from(pay_req.num_msat).unwrap_or(0),
+            created_at: u64::try_from(pay_req.timestamp).unwrap_or(0),
+            expiry_secs: u64::try_from(pay_req.expiry).unwrap_or(0),
+        })
+    }
+
+    async fn lookup_payment(
+        &self,
+        payment_hash: &str,
+    ) -> BackendResult<Option<backend::PaymentEntry>> {
+        let payment = track_payment(&mut self.client().await?, payment_hash).await?;
+        Ok(payment.as_ref().map(payment_entry_from_lnd))
+    }
+
+    async fn pay_invoice(
+        &self,
+        invoice: &str,
+        amount_msat: Option<u64>,
+        options: &PaymentConfig,
+        max_fee_msat: Option<u64>,
+    ) -> BackendResult<backend::Payment> {
+        let payment = pay_invoice(
+            &mut self.client().await?,
+            invoice,
+            amount_msat,
+            options,
+            max_fee_msat,
+        )
+        .await?;
+        Ok(payment_from_lnd(&payment))
+    }
+
+    async fn pay_keysend(
+        &self,
+        pubkey: &str,
+        amount_msat: u64,
+        preimage: Option<&str>,
+        tlv_records: &[(u64, String)],
+        options: &PaymentConfig,
+        max_fee_msat: Option<u64>,
+    ) -> BackendResult<backend::Payment> {
+        let payment = pay_keysend(
+            &mut self.client().await?,
+            pubkey,
+            amount_msat,
+            preimage,
+            tlv_records,
+            options,
+            max_fee_msat,
+        )
+        .await?;
+        Ok(payment_from_lnd(&payment))
+    }
+
+    async fn make_invoice(
+        &self,
+        amount_msat: u64,
+        description: Option<&str>,
+        description_hash: Option<&str>,
+        expiry_secs: Option<u64>,
+    ) -> BackendResult<backend::Invoice> {
+        let response = make_invoice(
+            &mut self.client().await?,
+            amount_msat,
+            description,
+            description_hash,
+            expiry_secs,
+        )
+        .await?;
+        let created_at = nostr_sdk::Timestamp::now().as_secs();
+        Ok(backend::Invoice {
+            invoice: response.payment_request,
+            payment_hash: hex::encode(response.r_hash),
+            description: description.map(str::to_string),
+            description_hash: description_hash.map(str::to_string),
+            amount_msat,
+            created_at,
+            expires_at: expiry_secs.map(|secs| created_at + secs),
+            ..Default::default()
+        })
+    }
+
+    async fn lookup_invoice(
+        &self,
+        payment_hash: Option<&str>,
+        invoice: Option<&str>,
+    ) -> BackendResult<backend::Invoice> {
+        let invoice = lookup_invoice(&mut self.client().await?, payment_hash, invoice).await?;
+        Ok(invoice_from_lnd(&invoice))
+    }
+
+    async fn wait_for_invoice_settlement(
+        &self,
+        payment_hash: &str,
+    ) -> BackendResult<backend::Invoice> {
+        let payment_hash = hex::decode(payment_hash).map_err(map_to_other)?;
+        let invoice = wait_for_invoice_settlement(&mut self.client().await?, payment_hash).await?;
+        Ok(invoice_from_lnd(&invoice))
+    }
+
+    async fn list_channels(&self) -> BackendResult<Vec<backend::Channel>> {
+        let channels = list_channels(&mut self.client().await?, false).await?;
+        Ok(channels
+            .iter()
+            .map(|channel| backend::Channel {
+                channel_id: channel.chan_id.to_string(),
+                remote_pubkey: channel.remote_pubkey.clone(),
+                channel_point: channel.channel_point.clone(),
+                capacity_sat: channel.capacity.max(0) as u64,
+                local_balance_sat: channel.local_balance.max(0) as u64,
+                remote_balance_sat: channel.remote_balance.max(0) as u64,
+                active: channel.active,
+                private: channel.private,
+            })
+            .collect())
+    }
+
+    async fn list_invoices(&self, limit: u64) -> BackendResult<Vec<backend::Invoice>> {
+        let invoices = list_invoices(&mut self.client().await?, limit).await?;
+        Ok(invoices.iter().rev().map(invoice_from_lnd).collect())
+    }
+
+    async fn list_payments(&self, limit: u64) -> BackendResult<Vec<backend::PaymentEntry>> {
+        let payments = list_payments(&mut self.client().await?, limit).await?;
+        Ok(payments.iter().rev().map(payment_entry_from_lnd).collect())
+    }
+
+    async fn open_channel(
+        &self,
+        pubkey: &str,
+        host: Option<&str>,
+        amount_sat: u64,
+        push_sat: u64,
+        private: bool,
+    ) -> BackendResult<String> {
+        if let Some(host) = host {
+            connect_peer(&mut self.client().await?, pubkey, host).await?;
+        }
+
+        let channel_point = open_channel(
+            &mut self.client().await?,
+            pubkey,
+            amount_sat,
+            push_sat,
+            private,
+        )
+        .await?;
+        let funding_txid = match channel_point.funding_txid {
+            Some(FundingTxid::FundingTxidBytes(txid)) => txid_to_hex(&txid),
+            Some(FundingTxid::FundingTxidStr(txid)) => txid,
+            None => String::new(),
+        };
+        Ok(format!("{funding_txid}:{}", channel_point.output_index))
+    }
+
+    async fn close_channel(&self, channel_point: &str, force: bool) -> BackendResult<String> {
+        let closing_txid = close_channel(&mut self.client().await?, channel_point, force).await?;
+        Ok(txid_to_hex(&closing_txid))
+    }
+}
+
+fn payment_from_lnd(payment: &lnrpc::Payment) -> backend::Payment {
+    backend::Payment {
+        payment_hash: payment.payment_hash.clone(),
+        preimage: payment.payment_preimage.clone(),
+        amount_msat: payment.value_msat.try_into().unwrap_or(0),
+        fees_msat: payment.fee_msat.try_into().unwrap_or(0),
+        created_at: nanos_to_secs(payment.creation_time_ns).unwrap_or(0),
+        settled_at: payment
+            .htlcs
+            .iter()
+            .filter_map(|htlc| nanos_to_secs(htlc.resolve_time_ns))
+            .max(),
+    }
+}
+
+fn payment_entry_from_lnd(payment: &lnrpc::Payment) -> backend::PaymentEntry {
+    backend::PaymentEntry {
+        payment: payment_from_lnd(payment),
+        invoice: Some(payment.payment_request.clone()).filter(|invoice| !invoice.is_empty()),
+        state: match PaymentStatus::try_from(payment.status).ok() {
+            Some(PaymentStatus::Succeeded) => backend::PaymentState::Succeeded,
+            Some(PaymentStatus::Failed) => backend::PaymentState::Failed,
+            _ => backend::PaymentState::InFlight,
+        },
+    }
+}
+
+fn invoice_from_lnd(invoice: &lnrpc::Invoice) -> backend::Invoice {
+    let state = match InvoiceState::try_from(invoice.state).ok() {
+        Some(InvoiceState::Settled) => backend::InvoiceState::Settled,
+        Some(InvoiceState::Canceled) => backend::InvoiceState::Canceled,
+        Some(InvoiceState::Accepted) => backend::InvoiceState::Accepted,
+        _ => backend::InvoiceState::Open,
+    };
+    let created_at = invoice.creation_date as u64;
+
+    backend::Invoice {
+        invoice: invoice.payment_request.clone(),
+        payment_hash: hex::encode(&invoice.r_hash),
+        preimage: if invoice.r_preimage.is_empty() {
+            None
+        } else {
+            Some(hex::encode(&invoice.r_preimage))
+        },
+        description: if invoice.memo.is_empty() {
+            None
+        } else {
+            Some(invoice.memo.clone())
+        },
+        description_hash: if invoice.description_hash.is_empty() {
+            None
+        } else {
+            Some(hex::encode(&invoice.description_hash))
+        },
+        amount_msat: invoice.value_msat as u64,
+        amount_paid_msat: invoice.amt_paid_msat as u64,
+        state,
+        created_at,
+        expires_at: if invoice.expiry > 0 {
+            Some(created_at + invoice.expiry as u64)
+        } else {
+            None
+        },
+        settled_at: if invoice.settle_date > 0 {
+            Some(invoice.settle_date as u64)
+        } else {
+            None
+        },
+    }
+}
+
+fn nanos_to_secs(nanos: i64) -> Option<u64> {
+    if nanos <= 0 {
+        return None;
+    }
+    Some((nanos / 1_000_000_000) as u64)
+}
+
+// False when the macaroon does not grant `entity:action`. The check itself needs the
+// macaroon:read permission, its errors mean the permission could not be checked.
+pub(crate) async fn check_macaroon_permission(
+    client: &mut LndClient,
+    macaroon: &[u8],
+    entity: &str,
+    action: &str,
+    full_method: &str,
+) -> LndResult<bool> {
+    let response = client
+        .lightning()
+        .check_macaroon_permissions(lnrpc::CheckMacPermRequest {
+            macaroon: macaroon.to_vec(),
+            permissions: vec![lnrpc::MacaroonPermission {
+                entity: entity.to_string(),
+                action: action.to_string(),
+            }],
+            full_method: full_method.to_string(),
+            ..Default::default()
+        })
+        .await?;
+
+    Ok(response.into_inner().valid)
+}
+
+// Returns the new macaroon, LND requires the macaroon:generate permission for it
+pub(crate) async fn bake_macaroon(
+    client: &mut LndClient,
+    permissions: &[(&str, &str)],
+) -> LndResult<Vec<u8>> {
+    let response = client
+        .lightning()
+        .bake_macaroon(lnrpc::BakeMacaroonRequest {
+            permissions: permissions
+                .iter()
+                .map(|(entity, action)| lnrpc::MacaroonPermission {
+                    entity: entity.to_string(),
+                    action: action.to_string(),
+                })
+                .collect(),
+            ..Default::default()
+        })
+        .await?
+        .into_inner();
+
+    hex::decode(response.macaroon).map_err(|e| map_to_other(e).into())
+}
 
-    let cert_bytes = fs::read(&cfg.lnd.cert_file)?;
-    let mac_bytes = fs::read(&cfg.lnd.macaroon_file)?;
+pub(crate) async fn connect(lnd: &LndConfig) -> LndResult<LndClient> {
+    let cert_bytes = fs::read(&lnd.cert_file)?;
+    let mac_bytes = fs::read(&lnd.macaroon_file)?;
 
     let cert = buffer_as_hex(cert_bytes);
     let macaroon = buffer_as_hex(mac_bytes);
-    let socket = cfg.lnd.uri.clone();
+    let socket = lnd.uri.clone();
 
     let client = lnd_grpc_rust::connect(cert, macaroon, socket)
         .await
@@ -259,14 +1099,14 @@ async fn connect_to_lnd() -> LndResult<lnd_grpc_rust::LndClient> {
 }
 
 fn map_to_other<E: ToString>(err: E) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, err.to_string())
+    io::Error::other(err.to_string())
 }
 
 fn buffer_as_hex(bytes: Vec<u8>) -> String {
-    return bytes
+    bytes
         .iter()
         .map(|b| format!("{:02x}", b))
-        .collect::<String>();
+        .collect::<String>()
 }
 
 /*
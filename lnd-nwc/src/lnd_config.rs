@@ -1,27 +1,452 @@
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use nostr_sdk::serde_json;
+use serde::Serialize;
 use std::fs;
-use std::path::Path;
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 
-use crate::config::{load_config, store_config};
+use crate::config::{LndConfig, LndTransport, load_config, store_config};
+use crate::nwc_types::{ADMIN_METHODS, NwcResponse};
+use crate::output::OutputFormat;
+use crate::{lnd, lnd_rest};
 
-pub fn store(cert_file: &str, macaroon_file: &str, uri: &str) {
-    let cert_path = fs::canonicalize(Path::new(cert_file))
-        .expect("Could not create the absolute path the certificate file");
-    let macaroon_path = fs::canonicalize(Path::new(macaroon_file))
-        .expect("Could not create the absolute path the macaroon file");
+type LndConfigResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
-    if !cert_path.exists() {
-        tracing::error!("Certificate file {:?} does not exist", cert_path);
-        return;
+const LNDCONNECT_SCHEME: &str = "lndconnect://";
+
+// Macaroon permissions needed by each NWC method, with an RPC requiring them
+const REQUIRED_PERMISSIONS: &[(&str, &str, &str)] = &[
+    ("get_info", "info:read", "/lnrpc.Lightning/GetInfo"),
+    (
+        "get_balance",
+        "offchain:read",
+        "/lnrpc.Lightning/ChannelBalance",
+    ),
+    (
+        "get_balance",
+        "onchain:read",
+        "/lnrpc.Lightning/WalletBalance",
+    ),
+    (
+        "pay_invoice",
+        "offchain:write",
+        "/routerrpc.Router/SendPaymentV2",
+    ),
+    (
+        "pay_keysend",
+        "offchain:write",
+        "/routerrpc.Router/SendPaymentV2",
+    ),
+    (
+        "make_invoice",
+        "invoices:write",
+        "/lnrpc.Lightning/AddInvoice",
+    ),
+    (
+        "lookup_invoice",
+        "invoices:read",
+        "/invoicesrpc.Invoices/LookupInvoiceV2",
+    ),
+    (
+        "list_channels",
+        "offchain:read",
+        "/lnrpc.Lightning/ListChannels",
+    ),
+    (
+        "open_channel",
+        "peers:write",
+        "/lnrpc.Lightning/ConnectPeer",
+    ),
+    (
+        "open_channel",
+        "onchain:write",
+        "/lnrpc.Lightning/OpenChannelSync",
+    ),
+    (
+        "open_channel",
+        "offchain:write",
+        "/lnrpc.Lightning/OpenChannelSync",
+    ),
+    (
+        "close_channel",
+        "onchain:write",
+        "/lnrpc.Lightning/CloseChannel",
+    ),
+    (
+        "close_channel",
+        "offchain:write",
+        "/lnrpc.Lightning/CloseChannel",
+    ),
+];
+
+// `node` names an entry of `lnd_nodes`, the `[lnd]` node is set without it
+pub async fn store(
+    node: Option<&str>,
+    cert_file: &str,
+    macaroon_file: &str,
+    uri: &str,
+    transport: LndTransport,
+) -> LndConfigResult<()> {
+    check_node_name(node)?;
+    let lnd_cfg = LndConfig {
+        uri: uri.to_string(),
+        cert_file: absolute_path(cert_file, "Certificate")?,
+        macaroon_file: absolute_path(macaroon_file, "Macaroon")?,
+        transport,
+    };
+    check_node(&lnd_cfg).await?;
+    save_node(node, lnd_cfg);
+    Ok(())
+}
+
+// lndconnect://host:port?cert=<base64url DER>&macaroon=<base64url>, as handed out by
+// node packages. The certificate and macaroon are written next to the config file,
+// readable by the owner only, and the node must answer before the settings are saved.
+// They are staged aside until then, so a failed check leaves the saved node working.
+pub async fn store_lndconnect(
+    node: Option<&str>,
+    lndconnect: &str,
+    transport: LndTransport,
+) -> LndConfigResult<()> {
+    check_node_name(node)?;
+    let (uri, cert, macaroon) = parse_lndconnect(lndconnect)?;
+    let config_file = confy::get_configuration_file_path("lnd-nwc", None)?;
+    let file_stem = match node {
+        Some(node) => format!("lndconnect-{node}"),
+        None => "lndconnect".to_string(),
+    };
+
+    let cert_file = match cert {
+        Some(der) => {
+            let cert_file = config_file.with_file_name(format!("{file_stem}.cert"));
+            write_private(&staged_path(&cert_file), der_to_pem(&der).as_bytes())?;
+            Some(cert_file)
+        }
+        // The REST proxy may present a certificate trusted by the system
+        None if transport == LndTransport::Rest => None,
+        None => {
+            return Err(Box::new(other(
+                "The lndconnect URI has no certificate, gRPC connections need one",
+            )));
+        }
+    };
+    let macaroon_file = config_file.with_file_name(format!("{file_stem}.macaroon"));
+    write_private(&staged_path(&macaroon_file), &macaroon)?;
+
+    let files = cert_file.iter().chain([&macaroon_file]).collect::<Vec<_>>();
+    let staged_cfg = LndConfig {
+        uri: uri.clone(),
+        cert_file: cert_file.as_deref().map(staged_path).unwrap_or_default(),
+        macaroon_file: staged_path(&macaroon_file),
+        transport,
+    };
+    if let Err(e) = check_node(&staged_cfg).await {
+        for file in &files {
+            let _ = fs::remove_file(staged_path(file));
+        }
+        return Err(e);
     }
+    for file in &files {
+        fs::rename(staged_path(file), file)?;
+    }
+
+    let lnd_cfg = LndConfig {
+        uri,
+        cert_file: cert_file.unwrap_or_default(),
+        macaroon_file,
+        transport,
+    };
+    save_node(node, lnd_cfg);
+    Ok(())
+}
+
+// Node names end up in the names of the lndconnect files
+fn check_node_name(node: Option<&str>) -> LndConfigResult<()> {
+    match node {
+        Some(node)
+            if node.is_empty()
+                || !node
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') =>
+        {
+            Err(Box::new(other(format!(
+                "Invalid node name `{node}`, use letters, digits, _ and -"
+            ))))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn staged_path(file: &Path) -> PathBuf {
+    let mut staged = file.as_os_str().to_owned();
+    staged.push(".new");
+    PathBuf::from(staged)
+}
+
+// The node must answer before its settings are saved, missing permissions only warn
+async fn check_node(lnd_cfg: &LndConfig) -> LndConfigResult<()> {
+    let alias = node_name(lnd_cfg)
+        .await
+        .map_err(|e| other(format!("Could not connect to {}: {e}", lnd_cfg.uri)))?;
+    tracing::info!("Connected to {alias}");
 
-    if !macaroon_path.exists() {
-        tracing::error!("Macaroon file {:?} does not exist", macaroon_path);
-        return;
+    match missing_permissions(lnd_cfg).await {
+        Ok(missing) => {
+            for (permission, methods) in missing {
+                tracing::warn!(
+                    "The macaroon lacks the {permission} permission needed by {}",
+                    methods.join(", ")
+                );
+            }
+        }
+        Err(e) => tracing::warn!("Could not check the macaroon permissions: {e}"),
     }
+    Ok(())
+}
 
+fn save_node(node: Option<&str>, lnd_cfg: LndConfig) {
     let mut cfg = load_config();
-    cfg.lnd.cert_file = cert_path;
-    cfg.lnd.macaroon_file = macaroon_path;
-    cfg.lnd.uri = uri.to_string();
+    match node {
+        Some(node) => {
+            cfg.lnd_nodes.insert(node.to_string(), lnd_cfg);
+        }
+        None => cfg.lnd = lnd_cfg,
+    }
     store_config(&cfg);
 }
+
+// Missing `entity:action` permissions and the enabled NWC methods needing them
+async fn missing_permissions(lnd_cfg: &LndConfig) -> LndConfigResult<Vec<(String, Vec<String>)>> {
+    let macaroon = fs::read(&lnd_cfg.macaroon_file)?;
+    let enabled = enabled_methods();
+
+    let mut missing: Vec<(String, Vec<String>)> = Vec::new();
+    let mut checked = Vec::new();
+    for (method, permission, full_method) in REQUIRED_PERMISSIONS {
+        if !enabled.contains(method) {
+            continue;
+        }
+
+        let (entity, action) = permission.split_once(':').unwrap_or((permission, ""));
+        if !checked.contains(permission) {
+            let granted = match lnd_cfg.transport {
+                LndTransport::Grpc => {
+                    lnd::check_macaroon_permission(
+                        &mut lnd::connect(lnd_cfg).await?,
+                        &macaroon,
+                        entity,
+                        action,
+                        full_method,
+                    )
+                    .await?
+                }
+                LndTransport::Rest => {
+                    lnd_rest::check_macaroon_permission(
+                        lnd_cfg,
+                        &macaroon,
+                        entity,
+                        action,
+                        full_method,
+                    )
+                    .await?
+                }
+            };
+            if !granted {
+                missing.push((permission.to_string(), Vec::new()));
+            }
+            checked.push(*permission);
+        }
+
+        if let Some((_, methods)) = missing.iter_mut().find(|(name, _)| name == permission) {
+            methods.push(method.to_string());
+        }
+    }
+
+    Ok(missing)
+}
+
+#[derive(Serialize)]
+struct BakedMacaroon {
+    file: PathBuf,
+    permissions: Vec<&'static str>,
+    connection: Option<String>,
+}
+
+// Bakes a macaroon limited to the permissions of the enabled NWC methods, or to their
+// read permissions, and assigns it to the connection when one is given. It is baked
+// by the connection's node, the `[lnd]` one otherwise. Admin connections also get
+// the channel management permissions.
+pub async fn bake_macaroon(
+    connection: Option<&str>,
+    file: Option<&str>,
+    read_only: bool,
+    output: OutputFormat,
+) -> LndConfigResult<()> {
+    let mut cfg = load_config();
+    let (node, admin) = match connection {
+        Some(name) => match cfg.connections.get(name) {
+            Some(connection_cfg) => (connection_cfg.lnd_node.clone(), connection_cfg.admin),
+            None => {
+                return Err(Box::new(other(format!("Connection {name} does not exist"))));
+            }
+        },
+        None => (None, false),
+    };
+    let lnd_cfg = cfg.lnd_node(node.as_deref()).cloned().ok_or_else(|| {
+        other(format!(
+            "LND node {} is not configured",
+            node.unwrap_or_default()
+        ))
+    })?;
+
+    let mut enabled = enabled_methods();
+    if admin {
+        enabled.extend(ADMIN_METHODS);
+    }
+    let mut permissions = REQUIRED_PERMISSIONS
+        .iter()
+        .filter(|(method, permission, _)| {
+            enabled.contains(method) && (!read_only || permission.ends_with(":read"))
+        })
+        .map(|(_, permission, _)| *permission)
+        .collect::<Vec<_>>();
+    permissions.sort_unstable();
+    permissions.dedup();
+
+    let pairs = permissions
+        .iter()
+        .filter_map(|permission| permission.split_once(':'))
+        .collect::<Vec<_>>();
+    let macaroon = match lnd_cfg.transport {
+        LndTransport::Grpc => {
+            lnd::bake_macaroon(&mut lnd::connect(&lnd_cfg).await?, &pairs).await?
+        }
+        LndTransport::Rest => lnd_rest::bake_macaroon(&lnd_cfg, &pairs).await?,
+    };
+
+    let file = match (file, connection) {
+        (Some(file), _) => PathBuf::from(file),
+        (None, Some(name)) => confy::get_configuration_file_path("lnd-nwc", None)?
+            .with_file_name("macaroons")
+            .join(format!("{name}.macaroon")),
+        (None, None) => {
+            return Err(Box::new(other("A file or a connection is needed")));
+        }
+    };
+    write_private(&file, &macaroon)?;
+    let file = fs::canonicalize(&file)?;
+
+    if let Some(connection_cfg) = connection.and_then(|name| cfg.connections.get_mut(name)) {
+        connection_cfg.macaroon_file = Some(file.clone());
+        store_config(&cfg);
+    }
+
+    let baked = BakedMacaroon {
+        file,
+        permissions,
+        connection: connection.map(str::to_string),
+    };
+    if output.is_json() {
+        println!("{}", serde_json::to_string_pretty(&baked)?);
+        return Ok(());
+    }
+
+    println!("Macaroon saved to {}", baked.file.display());
+    println!("\tPermissions: {}", baked.permissions.join(", "));
+    if let Some(name) = &baked.connection {
+        println!("\tUsed by {name}");
+    }
+    Ok(())
+}
+
+fn enabled_methods() -> Vec<&'static str> {
+    NwcResponse::default_responses()
+        .iter()
+        .map(NwcResponse::result_type)
+        .collect()
+}
+
+fn absolute_path(file: &str, kind: &str) -> LndConfigResult<PathBuf> {
+    fs::canonicalize(Path::new(file))
+        .map_err(|e| other(format!("{kind} file {file} cannot be read: {e}")).into())
+}
+
+fn parse_lndconnect(lndconnect: &str) -> LndConfigResult<(String, Option<Vec<u8>>, Vec<u8>)> {
+    let rest = lndconnect
+        .strip_prefix(LNDCONNECT_SCHEME)
+        .ok_or_else(|| other(format!("URI must start with {LNDCONNECT_SCHEME}")))?;
+    let (host, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let host = host.trim_end_matches('/');
+    if host.is_empty() {
+        return Err(Box::new(other("The lndconnect URI has no host")));
+    }
+
+    let mut cert = None;
+    let mut macaroon = None;
+    for param in query.split('&') {
+        let Some((key, value)) = param.split_once('=') else {
+            continue;
+        };
+        let value = urlencoding::decode(value)?;
+        let bytes = URL_SAFE_NO_PAD
+            .decode(value.trim_end_matches('='))
+            .map_err(|e| other(format!("Invalid {key} in the lndconnect URI: {e}")))?;
+        match key {
+            "cert" => cert = Some(bytes),
+            "macaroon" => macaroon = Some(bytes),
+            _ => {}
+        }
+    }
+
+    let macaroon = macaroon.ok_or_else(|| other("The lndconnect URI has no macaroon"))?;
+    Ok((host.to_string(), cert, macaroon))
+}
+
+fn der_to_pem(der: &[u8]) -> String {
+    let encoded = STANDARD.encode(der);
+    let mut pem = String::from("-----BEGIN CERTIFICATE-----\n");
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(&String::from_utf8_lossy(line));
+        pem.push('\n');
+    }
+    pem.push_str("-----END CERTIFICATE-----\n");
+    pem
+}
+
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // An existing file keeps its mode when opened
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(contents)
+}
+
+async fn node_name(lnd_cfg: &LndConfig) -> LndConfigResult<String> {
+    match lnd_cfg.transport {
+        LndTransport::Grpc => {
+            let info = lnd::get_info(&mut lnd::connect(lnd_cfg).await?).await?;
+            Ok(format!("{} ({})", info.alias, info.identity_pubkey))
+        }
+        LndTransport::Rest => {
+            let info = lnd_rest::get_info(lnd_cfg).await?;
+            Ok(format!(
+                "{} ({})",
+                info["alias"].as_str().unwrap_or_default(),
+                info["identity_pubkey"].as_str().unwrap_or_default()
+            ))
+        }
+    }
+}
+
+fn other<E: ToString>(err: E) -> io::Error {
+    io::Error::other(err.to_string())
+}
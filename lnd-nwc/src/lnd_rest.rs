@@ -0,0 +1,642 @@
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE};
+use nostr_sdk::serde_json::{self, Value, json};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+
+use nostr_sdk::Timestamp;
+
+use crate::backend::{
+    BackendResult, Balance, Channel, DecodedInvoice, Invoice, InvoiceState, NodeInfo, Payment,
+    PaymentEntry, PaymentState, WalletBackend, payment_failed,
+};
+use crate::config::{LndConfig, PaymentConfig};
+use crate::lnd::{
+    ALREADY_CONNECTED, DEFAULT_PAYMENT_TIMEOUT_SECS, PAYMENT_LOOKUP_TIMEOUT_SECS,
+    PAYMENT_NOT_INITIATED, fee_limit_msat, keysend_records, parse_channel_point, txid_to_hex,
+};
+
+// `WalletBackend` over the LND REST proxy, for nodes that only expose it (e.g. behind
+// a reverse proxy). Bytes fields are base64 encoded and 64-bit integers are strings.
+
+pub struct LndRestBackend {
+    config: LndConfig,
+    // Built on first use and shared by the requests, its connections are pooled
+    client: OnceCell<RestClient>,
+}
+
+struct RestClient {
+    http: reqwest::Client,
+    base_url: String,
+    macaroon: String,
+}
+
+// Streaming endpoints answer with one `{"result": ...}` JSON object per line
+struct ResultStream {
+    response: reqwest::Response,
+    buffer: Vec<u8>,
+}
+
+impl RestClient {
+    fn connect(cfg: &LndConfig) -> BackendResult<Self> {
+        let macaroon = hex::encode(fs::read(&cfg.macaroon_file)?);
+
+        // Without a certificate the proxy must present one trusted by the system
+        let mut builder = reqwest::Client::builder();
+        if !cfg.cert_file.as_os_str().is_empty() {
+            let cert = reqwest::Certificate::from_pem(&fs::read(&cfg.cert_file)?)?;
+            builder = builder.add_root_certificate(cert);
+        }
+
+        let base_url = if cfg.uri.starts_with("http://") || cfg.uri.starts_with("https://") {
+            cfg.uri.trim_end_matches('/').to_string()
+        } else {
+            format!("https://{}", cfg.uri.trim_end_matches('/'))
+        };
+
+        Ok(RestClient {
+            http: builder.build()?,
+            base_url,
+            macaroon,
+        })
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        self.http
+            .request(method, format!("{}{path}", self.base_url))
+            .header("Grpc-Metadata-macaroon", &self.macaroon)
+    }
+
+    async fn get(&self, path: &str) -> BackendResult<Value> {
+        let response = checked(self.request(reqwest::Method::GET, path).send().await?).await?;
+        Ok(response.json().await?)
+    }
+
+    async fn post(&self, path: &str, body: &Value) -> BackendResult<Value> {
+        let request = self.request(reqwest::Method::POST, path).json(body);
+        let response = checked(request.send().await?).await?;
+        Ok(response.json().await?)
+    }
+
+    async fn stream(&self, request: reqwest::RequestBuilder) -> BackendResult<ResultStream> {
+        let response = checked(request.send().await?).await?;
+        Ok(ResultStream {
+            response,
+            buffer: Vec::new(),
+        })
+    }
+}
+
+impl ResultStream {
+    async fn next(&mut self) -> BackendResult<Option<Value>> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                let line = self.buffer.drain(..=end).collect::<Vec<u8>>();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+
+                let mut message: Value = serde_json::from_slice(&line)?;
+                if let Some(error) = message.get("error") {
+                    return Err(Box::new(other(error_message(error))));
+                }
+                return Ok(Some(message["result"].take()));
+            }
+
+            match self.response.chunk().await? {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl LndRestBackend {
+    pub fn new(config: &LndConfig) -> Self {
+        LndRestBackend {
+            config: config.clone(),
+            client: OnceCell::new(),
+        }
+    }
+
+    async fn client(&self) -> BackendResult<&RestClient> {
+        self.client
+            .get_or_try_init(|| async { RestClient::connect(&self.config) })
+            .await
+    }
+
+    async fn send_payment(
+        &self,
+        mut request: Value,
+        options: &PaymentConfig,
+    ) -> BackendResult<Payment> {
+        request["timeout_seconds"] = json!(
+            options
+                .timeout_seconds
+                .map(|secs| i32::try_from(secs).unwrap_or(i32::MAX))
+                .unwrap_or(DEFAULT_PAYMENT_TIMEOUT_SECS)
+        );
+        request["max_parts"] = json!(options.max_parts.unwrap_or_default());
+        request["outgoing_chan_ids"] = json!(options.outgoing_chan_ids);
+        request["allow_self_payment"] = json!(options.allow_self_payment);
+        if let Some(last_hop) = &options.last_hop_pubkey {
+            let last_hop_pubkey = hex::decode(last_hop).map_err(other)?;
+            if last_hop_pubkey.len() != 33 {
+                return Err(Box::new(other("Last hop pubkey must be 33 bytes")));
+            }
+            request["last_hop_pubkey"] = json!(STANDARD.encode(last_hop_pubkey));
+        }
+
+        let client = self.client().await?;
+        let mut stream = client
+            .stream(
+                client
+                    .request(reqwest::Method::POST, "/v2/router/send")
+                    .json(&request),
+            )
+            .await?;
+
+        while let Some(payment) = stream.next().await? {
+            match payment["status"].as_str() {
+                Some("SUCCEEDED") => return Ok(payment_from_rest(&payment)),
+                Some("FAILED") => {
+                    return Err(payment_failed(format!(
+                        "Payment failed with reason {}",
+                        payment["failure_reason"].as_str().unwrap_or_default()
+                    )));
+                }
+                _ => continue,
+            }
+        }
+
+        Err(Box::new(other("Failed to receive payment")))
+    }
+}
+
+pub(crate) async fn get_info(cfg: &LndConfig) -> BackendResult<Value> {
+    RestClient::connect(cfg)?.get("/v1/getinfo").await
+}
+
+pub(crate) async fn check_macaroon_permission(
+    cfg: &LndConfig,
+    macaroon: &[u8],
+    entity: &str,
+    action: &str,
+    full_method: &str,
+) -> BackendResult<bool> {
+    let client = RestClient::connect(cfg)?;
+    let request = json!({
+        "macaroon": STANDARD.encode(macaroon),
+        "permissions": [{ "entity": entity, "action": action }],
+        "full_method": full_method,
+    });
+    let response = client
+        .post("/v1/macaroon/checkpermissions", &request)
+        .await?;
+    Ok(response["valid"].as_bool().unwrap_or(false))
+}
+
+pub(crate) async fn bake_macaroon(
+    cfg: &LndConfig,
+    permissions: &[(&str, &str)],
+) -> BackendResult<Vec<u8>> {
+    let permissions = permissions
+        .iter()
+        .map(|(entity, action)| json!({ "entity": entity, "action": action }))
+        .collect::<Vec<_>>();
+    let response = RestClient::connect(cfg)?
+        .post("/v1/macaroon", &json!({ "permissions": permissions }))
+        .await?;
+    hex::decode(string(&response["macaroon"])).map_err(|e| other(e).into())
+}
+
+#[async_trait]
+impl WalletBackend for LndRestBackend {
+    async fn get_info(&self) -> BackendResult<NodeInfo> {
+        let info = get_info(&self.config).await?;
+        Ok(NodeInfo {
+            network: info["chains"][0]["network"].as_str().map(str::to_string),
+        })
+    }
+
+    async fn balance(&self) -> BackendResult<Balance> {
+        let client = self.client().await?;
+        let channels_msat =
+            int(&client.get("/v1/balance/channels").await?["local_balance"]["msat"]);
+        let spendable_sat = client.get("/v1/channels?active_only=true").await?["channels"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|channel| {
+                let reserve = int(&channel["local_constraints"]["chan_reserve_sat"]);
+                (int(&channel["local_balance"]) - reserve).max(0)
+            })
+            .sum::<i64>();
+        let onchain_sat = int(&client.get("/v1/balance/blockchain").await?["confirmed_balance"]);
+
+        Ok(Balance {
+            channels_msat,
+            spendable_msat: spendable_sat.saturating_mul(1000),
+            onchain_msat: onchain_sat.saturating_mul(1000),
+        })
+    }
+
+    async fn decode_invoice(&self, invoice: &str) -> BackendResult<DecodedInvoice> {
+        let pay_req = self
+            .client()
+            .await?
+            .get(&format!("/v1/payreq/{}", urlencoding::encode(invoice)))
+            .await?;
+        Ok(DecodedInvoice {
+            payment_hash: string(&pay_req["payment_hash"]),
+            amount_msat: int(&pay_req["num_msat"]).max(0) as u64,
+            created_at: int(&pay_req["timestamp"]).max(0) as u64,
+            expiry_secs: int(&pay_req["expiry"]).max(0) as u64,
+        })
+    }
+
+    async fn lookup_payment(&self, payment_hash: &str) -> BackendResult<Option<PaymentEntry>> {
+        let payment_hash = hex::decode(payment_hash).map_err(other)?;
+        let client = self.client().await?;
+        let path = format!("/v2/router/track/{}", URL_SAFE.encode(payment_hash));
+
+        // The first update is the current state of the payment
+        let track = async {
+            let mut stream = client
+                .stream(client.request(reqwest::Method::GET, &path))
+                .await?;
+            stream.next().await
+        };
+        let payment = tokio::time::timeout(Duration::from_secs(PAYMENT_LOOKUP_TIMEOUT_SECS), track)
+            .await
+            .map_err(|_| other("Timed out looking up the payment"))?;
+        match payment {
+            Ok(payment) => Ok(payment.as_ref().map(payment_entry_from_rest)),
+            Err(e) if e.to_string().contains(PAYMENT_NOT_INITIATED) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn pay_invoice(
+        &self,
+        invoice: &str,
+        amount_msat: Option<u64>,
+        options: &PaymentConfig,
+        max_fee_msat: Option<u64>,
+    ) -> BackendResult<Payment> {
+        let request = json!({
+            "payment_request": invoice,
+            "amt_msat": amount_msat.unwrap_or(0).to_string(),
+            "fee_limit_msat": fee_limit_msat(amount_msat, max_fee_msat).to_string(),
+        });
+        self.send_payment(request, options).await
+    }
+
+    async fn pay_keysend(
+        &self,
+        pubkey: &str,
+        amount_msat: u64,
+        preimage: Option<&str>,
+        tlv_records: &[(u64, String)],
+        options: &PaymentConfig,
+        max_fee_msat: Option<u64>,
+    ) -> BackendResult<Payment> {
+        let dest = hex::decode(pubkey).map_err(other)?;
+        if dest.len() != 33 {
+            return Err(Box::new(other("Destination pubkey must be 33 bytes")));
+        }
+
+        let (payment_preimage, custom_records) = keysend_records(preimage, tlv_records)?;
+        let dest_custom_records = custom_records
+            .into_iter()
+            .map(|(typ, value)| (typ.to_string(), Value::String(STANDARD.encode(value))))
+            .collect::<serde_json::Map<_, _>>();
+
+        let request = json!({
+            "dest": STANDARD.encode(dest),
+            "amt_msat": amount_msat.to_string(),
+            "payment_hash": STANDARD.encode(Sha256::digest(&payment_preimage)),
+            "dest_custom_records": dest_custom_records,
+            "fee_limit_msat": fee_limit_msat(Some(amount_msat), max_fee_msat).to_string(),
+        });
+        self.send_payment(request, options).await
+    }
+
+    async fn make_invoice(
+        &self,
+        amount_msat: u64,
+        description: Option<&str>,
+        description_hash: Option<&str>,
+        expiry_secs: Option<u64>,
+    ) -> BackendResult<Invoice> {
+        let mut request = json!({
+            "memo": description.unwrap_or_default(),
+            "value_msat": amount_msat.to_string(),
+            "expiry": expiry_secs.unwrap_or_default().to_string(),
+        });
+        if let Some(hash) = description_hash.filter(|hash| !hash.is_empty()) {
+            request["description_hash"] = json!(STANDARD.encode(hex::decode(hash).map_err(other)?));
+        }
+
+        let response = self.client().await?.post("/v1/invoices", &request).await?;
+        let created_at = Timestamp::now().as_secs();
+        Ok(Invoice {
+            invoice: string(&response["payment_request"]),
+            payment_hash: base64_to_hex(&response["r_hash"]).unwrap_or_default(),
+            description: description.map(str::to_string),
+            description_hash: description_hash.map(str::to_string),
+            amount_msat,
+            created_at,
+            expires_at: expiry_secs.map(|secs| created_at + secs),
+            ..Default::default()
+        })
+    }
+
+    async fn lookup_invoice(
+        &self,
+        payment_hash: Option<&str>,
+        invoice: Option<&str>,
+    ) -> BackendResult<Invoice> {
+        let payment_hash = match (payment_hash, invoice) {
+            (Some(payment_hash), _) => payment_hash.to_string(),
+            (None, Some(invoice)) => self.decode_invoice(invoice).await?.payment_hash,
+            (None, None) => {
+                return Err(Box::new(other("Missing payment hash or payment request")));
+            }
+        };
+
+        let payment_hash = hex::decode(payment_hash).map_err(other)?;
+        let invoice = self
+            .client()
+            .await?
+            .get(&format!(
+                "/v2/invoices/lookup?payment_hash={}",
+                urlencoding::encode(&URL_SAFE.encode(payment_hash))
+            ))
+            .await?;
+        Ok(invoice_from_rest(&invoice))
+    }
+
+    async fn wait_for_invoice_settlement(&self, payment_hash: &str) -> BackendResult<Invoice> {
+        let payment_hash = hex::decode(payment_hash).map_err(other)?;
+        let client = self.client().await?;
+        let path = format!("/v2/invoices/subscribe/{}", URL_SAFE.encode(payment_hash));
+        let mut stream = client
+            .stream(client.request(reqwest::Method::GET, &path))
+            .await?;
+
+        while let Some(invoice) = stream.next().await? {
+            match invoice["state"].as_str() {
+                Some("SETTLED") => return Ok(invoice_from_rest(&invoice)),
+                Some("CANCELED") => return Err(Box::new(other("Invoice canceled"))),
+                _ => continue,
+            }
+        }
+
+        Err(Box::new(other("Invoice stream ended before settlement")))
+    }
+
+    async fn list_channels(&self) -> BackendResult<Vec<Channel>> {
+        let response = self.client().await?.get("/v1/channels").await?;
+        Ok(response["channels"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|channel| Channel {
+                channel_id: string(&channel["chan_id"]),
+                remote_pubkey: string(&channel["remote_pubkey"]),
+                channel_point: string(&channel["channel_point"]),
+                capacity_sat: int(&channel["capacity"]).max(0) as u64,
+                local_balance_sat: int(&channel["local_balance"]).max(0) as u64,
+                remote_balance_sat: int(&channel["remote_balance"]).max(0) as u64,
+                active: channel["active"].as_bool().unwrap_or(false),
+                private: channel["private"].as_bool().unwrap_or(false),
+            })
+            .collect())
+    }
+
+    async fn list_invoices(&self, limit: u64) -> BackendResult<Vec<Invoice>> {
+        let response = self
+            .client()
+            .await?
+            .get(&format!(
+                "/v1/invoices?num_max_invoices={limit}&reversed=true"
+            ))
+            .await?;
+        Ok(response["invoices"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .rev()
+            .map(invoice_from_rest)
+            .collect())
+    }
+
+    async fn list_payments(&self, limit: u64) -> BackendResult<Vec<PaymentEntry>> {
+        let response = self
+            .client()
+            .await?
+            .get(&format!(
+                "/v1/payments?max_payments={limit}&reversed=true&include_incomplete=true"
+            ))
+            .await?;
+        Ok(response["payments"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .rev()
+            .map(payment_entry_from_rest)
+            .collect())
+    }
+
+    async fn open_channel(
+        &self,
+        pubkey: &str,
+        host: Option<&str>,
+        amount_sat: u64,
+        push_sat: u64,
+        private: bool,
+    ) -> BackendResult<String> {
+        let client = self.client().await?;
+        if let Some(host) = host {
+            let request = json!({ "addr": { "pubkey": pubkey, "host": host } });
+            match client.post("/v1/peers", &request).await {
+                Err(e) if !e.to_string().contains(ALREADY_CONNECTED) => return Err(e),
+                _ => {}
+            }
+        }
+
+        let request = json!({
+            "node_pubkey": STANDARD.encode(hex::decode(pubkey).map_err(other)?),
+            "local_funding_amount": amount_sat.to_string(),
+            "push_sat": push_sat.to_string(),
+            "private": private,
+        });
+        let channel_point = client.post("/v1/channels", &request).await?;
+        let funding_txid = match channel_point["funding_txid_str"].as_str() {
+            Some(txid) => txid.to_string(),
+            None => txid_from_rest(&channel_point["funding_txid_bytes"]),
+        };
+        Ok(format!(
+            "{funding_txid}:{}",
+            int(&channel_point["output_index"])
+        ))
+    }
+
+    async fn close_channel(&self, channel_point: &str, force: bool) -> BackendResult<String> {
+        let (funding_txid, output_index) = parse_channel_point(channel_point)?;
+        let client = self.client().await?;
+        let mut stream = client
+            .stream(client.request(
+                reqwest::Method::DELETE,
+                &format!("/v1/channels/{funding_txid}/{output_index}?force={force}"),
+            ))
+            .await?;
+
+        while let Some(update) = stream.next().await? {
+            if let Some(txid) = update["close_pending"].get("txid") {
+                return Ok(txid_from_rest(txid));
+            }
+            if let Some(txid) = update["chan_close"].get("closing_txid") {
+                return Ok(txid_from_rest(txid));
+            }
+        }
+
+        Err(Box::new(other(
+            "Close stream ended before the closing transaction",
+        )))
+    }
+}
+
+fn payment_from_rest(payment: &Value) -> Payment {
+    Payment {
+        payment_hash: string(&payment["payment_hash"]),
+        preimage: string(&payment["payment_preimage"]),
+        amount_msat: int(&payment["value_msat"]).max(0) as u64,
+        fees_msat: int(&payment["fee_msat"]).max(0) as u64,
+        created_at: nanos_to_secs(int(&payment["creation_time_ns"])).unwrap_or(0),
+        settled_at: payment["htlcs"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|htlc| nanos_to_secs(int(&htlc["resolve_time_ns"])))
+            .max(),
+    }
+}
+
+fn payment_entry_from_rest(payment: &Value) -> PaymentEntry {
+    PaymentEntry {
+        payment: payment_from_rest(payment),
+        invoice: payment["payment_request"]
+            .as_str()
+            .filter(|invoice| !invoice.is_empty())
+            .map(str::to_string),
+        state: match payment["status"].as_str() {
+            Some("SUCCEEDED") => PaymentState::Succeeded,
+            Some("FAILED") => PaymentState::Failed,
+            _ => PaymentState::InFlight,
+        },
+    }
+}
+
+fn invoice_from_rest(invoice: &Value) -> Invoice {
+    let state = match invoice["state"].as_str() {
+        Some("SETTLED") => InvoiceState::Settled,
+        Some("CANCELED") => InvoiceState::Canceled,
+        Some("ACCEPTED") => InvoiceState::Accepted,
+        _ => InvoiceState::Open,
+    };
+    let created_at = int(&invoice["creation_date"]).max(0) as u64;
+    let expiry = int(&invoice["expiry"]);
+    let settle_date = int(&invoice["settle_date"]);
+
+    Invoice {
+        invoice: string(&invoice["payment_request"]),
+        payment_hash: base64_to_hex(&invoice["r_hash"]).unwrap_or_default(),
+        preimage: base64_to_hex(&invoice["r_preimage"]),
+        description: invoice["memo"]
+            .as_str()
+            .filter(|memo| !memo.is_empty())
+            .map(str::to_string),
+        description_hash: base64_to_hex(&invoice["description_hash"]),
+        amount_msat: int(&invoice["value_msat"]).max(0) as u64,
+        amount_paid_msat: int(&invoice["amt_paid_msat"]).max(0) as u64,
+        state,
+        created_at,
+        expires_at: if expiry > 0 {
+            Some(created_at + expiry as u64)
+        } else {
+            None
+        },
+        settled_at: if settle_date > 0 {
+            Some(settle_date as u64)
+        } else {
+            None
+        },
+    }
+}
+
+async fn checked(response: reqwest::Response) -> BackendResult<reqwest::Response> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+
+    let status = response.status();
+    let body: Value = response.json().await.unwrap_or_default();
+    Err(Box::new(other(format!(
+        "LND REST request failed ({status}): {}",
+        error_message(&body)
+    ))))
+}
+
+fn error_message(error: &Value) -> String {
+    error["message"]
+        .as_str()
+        .unwrap_or("unknown error")
+        .to_string()
+}
+
+// 64-bit integers are strings, smaller ones are numbers
+fn int(value: &Value) -> i64 {
+    match value {
+        Value::Number(number) => number.as_i64().unwrap_or(0),
+        Value::String(text) => text.parse().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+fn string(value: &Value) -> String {
+    value.as_str().unwrap_or_default().to_string()
+}
+
+// None for missing or empty bytes fields
+fn base64_to_hex(value: &Value) -> Option<String> {
+    let bytes = STANDARD.decode(value.as_str()?).ok()?;
+    if bytes.is_empty() {
+        None
+    } else {
+        Some(hex::encode(bytes))
+    }
+}
+
+fn txid_from_rest(value: &Value) -> String {
+    let bytes = STANDARD
+        .decode(value.as_str().unwrap_or_default())
+        .unwrap_or_default();
+    txid_to_hex(&bytes)
+}
+
+fn nanos_to_secs(nanos: i64) -> Option<u64> {
+    if nanos <= 0 {
+        return None;
+    }
+    Some((nanos / 1_000_000_000) as u64)
+}
+
+fn other<E: ToString>(err: E) -> io::Error {
+    io::Error::other(err.to_string())
+}
@@ -1,12 +1,22 @@
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
+use std::process::ExitCode;
 
+mod backend;
+mod cln;
 mod config;
+mod db;
+mod fake;
+mod ledger;
 mod lnd;
 mod lnd_config;
+mod lnd_rest;
 mod nostr;
 mod nostr_config;
 mod nwc_types;
+mod output;
+mod qr;
+mod secrets;
 mod uri;
 mod uri_config;
 
@@ -16,6 +26,9 @@ mod uri_config;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Format of the command results
+    #[arg(long, global = true, value_enum, default_value_t = output::OutputFormat::Text)]
+    output: output::OutputFormat,
 }
 
 #[derive(Subcommand)]
@@ -32,6 +45,10 @@ enum Commands {
         #[command(subcommand)]
         action: UriAction,
     },
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
 }
 
 #[derive(Subcommand)]
@@ -39,6 +56,8 @@ enum DaemonAction {
     Start {
         #[arg(short = 'p', long)]
         pid_file: String,
+        #[arg(long)]
+        passphrase_fd: Option<i32>,
     },
     Stop {
         #[arg(short = 'p', long)]
@@ -53,14 +72,61 @@ enum DaemonAction {
 #[derive(Subcommand)]
 enum LndAction {
     Set {
-        #[arg(short = 'c', long)]
-        cert: String,
-        #[arg(short = 'm', long)]
-        macaroon: String,
-        #[arg(short = 'u', long)]
-        uri: String,
-    },
-    Info,
+        /// Name of an additional node, the default node is set without it
+        #[arg(long)]
+        node: Option<String>,
+        #[arg(short = 'c', long, required_unless_present = "lndconnect")]
+        cert: Option<String>,
+        #[arg(short = 'm', long, required_unless_present = "lndconnect")]
+        macaroon: Option<String>,
+        #[arg(short = 'u', long, required_unless_present = "lndconnect")]
+        uri: Option<String>,
+        /// lndconnect:// URI embedding the node address, certificate and macaroon
+        #[arg(long, conflicts_with_all = ["cert", "macaroon", "uri"])]
+        lndconnect: Option<String>,
+        /// Talk to the REST proxy, the URI being its base URL
+        #[arg(long)]
+        rest: bool,
+    },
+    Info {
+        /// Name of an additional node, the default node otherwise
+        #[arg(long)]
+        node: Option<String>,
+    },
+    Balance {
+        #[arg(long)]
+        node: Option<String>,
+    },
+    Channels {
+        #[arg(long)]
+        node: Option<String>,
+    },
+    Invoices {
+        #[arg(long)]
+        node: Option<String>,
+        /// Number of the latest invoices listed
+        #[arg(long, default_value_t = 20)]
+        limit: u64,
+    },
+    Payments {
+        #[arg(long)]
+        node: Option<String>,
+        /// Number of the latest payments listed
+        #[arg(long, default_value_t = 20)]
+        limit: u64,
+    },
+    /// Bake a macaroon limited to the permissions needed by the NWC methods
+    BakeMacaroon {
+        /// Connection using the macaroon instead of the node's
+        #[arg(short = 'n', long, required_unless_present = "file")]
+        connection: Option<String>,
+        /// Where to save the macaroon, next to the config by default
+        #[arg(short = 'f', long)]
+        file: Option<String>,
+        /// Only grant the read permissions
+        #[arg(long)]
+        read_only: bool,
+    },
 }
 
 #[derive(Subcommand)]
@@ -68,60 +134,309 @@ enum UriAction {
     Create {
         #[arg(short = 'n', long)]
         name: String,
-        #[arg(short = 'r', long)]
-        relay: String,
+        /// Relay used by the connection, may be repeated
+        #[arg(short = 'r', long = "relay", required = true)]
+        relays: Vec<String>,
+        #[arg(long)]
+        timeout: Option<u32>,
+        #[arg(long)]
+        max_parts: Option<u32>,
+        /// Short channel id, as a number, the payments may leave through. May be repeated
+        #[arg(long = "outgoing-chan-id")]
+        outgoing_chan_ids: Vec<u64>,
+        /// Hex pubkey of the node the payments must reach the destination through
+        #[arg(long)]
+        last_hop_pubkey: Option<String>,
+        #[arg(long)]
+        allow_self_payment: bool,
+        #[arg(long, value_enum, default_value_t = config::BalanceMode::Total)]
+        balance_mode: config::BalanceMode,
+        /// Cap of the capped balance mode, in msat
+        #[arg(long, required_if_eq("balance_mode", "capped"))]
+        balance_cap: Option<u64>,
+        #[arg(long)]
+        sub_wallet: bool,
+        /// Named LND node serving the connection, the default node otherwise
+        #[arg(long)]
+        lnd_node: Option<String>,
+        /// Allow the list_channels, open_channel and close_channel methods
+        #[arg(long)]
+        admin: bool,
+        /// Unix timestamp after which the connection's requests are refused
+        #[arg(long, conflicts_with = "expires_in")]
+        expires_at: Option<u64>,
+        /// Number of seconds after which the connection's requests are refused
+        #[arg(long)]
+        expires_in: Option<u64>,
+        /// Display the URI as a QR code
+        #[arg(long)]
+        qr: bool,
+        /// Save the URI as a QR code, .png or .svg
+        #[arg(long)]
+        qr_file: Option<PathBuf>,
     },
     Remove {
         #[arg(short = 'n', long)]
         name: String,
     },
     List,
+    /// Show the settings and usage of a connection
+    Show {
+        #[arg(short = 'n', long)]
+        name: String,
+    },
+    /// Rename a connection, keeping its history
+    Rename {
+        #[arg(short = 'n', long)]
+        name: String,
+        #[arg(long)]
+        new_name: String,
+    },
+    /// Create a new secret for a connection, keeping its settings
+    Rotate {
+        #[arg(short = 'n', long)]
+        name: String,
+        /// Display the new URI as a QR code
+        #[arg(long)]
+        qr: bool,
+        /// Save the new URI as a QR code, .png or .svg
+        #[arg(long)]
+        qr_file: Option<PathBuf>,
+    },
+    /// Refuse the requests of a connection until it is enabled again
+    Disable {
+        #[arg(short = 'n', long)]
+        name: String,
+    },
+    /// Accept the requests of a disabled connection again
+    Enable {
+        #[arg(short = 'n', long)]
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Encrypt the secrets of a plaintext config with a passphrase
+    Encrypt,
+    /// Replace stored connection URIs by their client pubkey
+    Migrate,
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt().init();
+async fn main() -> ExitCode {
+    // Logs go to stderr so that stdout only carries the command results
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
 
     let cli = Cli::parse();
+    let output = cli.output;
 
     match cli.command {
         Commands::Uri { action } => match action {
-            UriAction::Create { name, relay } => {
-                let _ = nostr_config::load_or_generate_keys().expect("Could not retrieve keys");
-                let _ = uri_config::create_and_save(&name, &relay);
-            }
-            UriAction::Remove { name } => {
-                let _ = uri_config::remove_and_save(&name);
+            UriAction::Create {
+                name,
+                relays,
+                timeout,
+                max_parts,
+                outgoing_chan_ids,
+                last_hop_pubkey,
+                allow_self_payment,
+                balance_mode,
+                balance_cap,
+                sub_wallet,
+                lnd_node,
+                admin,
+                expires_at,
+                expires_in,
+                qr,
+                qr_file,
+            } => {
+                let payment = config::PaymentConfig {
+                    timeout_seconds: timeout,
+                    max_parts,
+                    outgoing_chan_ids,
+                    last_hop_pubkey,
+                    allow_self_payment,
+                };
+                let balance = config::BalanceConfig {
+                    mode: balance_mode,
+                    cap_msat: balance_cap,
+                };
+                let expires_at = expires_at.or_else(|| {
+                    expires_in.map(|seconds| nostr_sdk::Timestamp::now().as_secs() + seconds)
+                });
+                let connection = config::ConnectionConfig {
+                    sub_wallet,
+                    lnd_node,
+                    admin,
+                    payment,
+                    balance,
+                    expires_at,
+                    ..Default::default()
+                };
+                let qr = qr::QrOutput {
+                    terminal: qr,
+                    file: qr_file,
+                };
+                let result = nostr_config::load_or_generate_keys().and_then(|_| {
+                    uri_config::create_and_save(&name, &relays, connection, &qr, output)
+                });
+                output::exit_code(result, output, &format!("Could not create {name}"))
             }
-            UriAction::List => {
-                let _ = uri_config::load_and_display();
+            UriAction::Remove { name } => output::exit_code(
+                uri_config::remove_and_save(&name),
+                output,
+                &format!("Could not remove {name}"),
+            ),
+            UriAction::List => output::exit_code(
+                uri_config::load_and_display(output),
+                output,
+                "Could not list the URIs",
+            ),
+            UriAction::Show { name } => output::exit_code(
+                uri_config::show(&name, output),
+                output,
+                &format!("Could not show {name}"),
+            ),
+            UriAction::Rename { name, new_name } => output::exit_code(
+                uri_config::rename_and_save(&name, &new_name),
+                output,
+                &format!("Could not rename {name}"),
+            ),
+            UriAction::Rotate { name, qr, qr_file } => {
+                let qr = qr::QrOutput {
+                    terminal: qr,
+                    file: qr_file,
+                };
+                output::exit_code(
+                    uri_config::rotate_and_save(&name, &qr, output),
+                    output,
+                    &format!("Could not rotate {name}"),
+                )
             }
+            UriAction::Disable { name } => output::exit_code(
+                uri_config::set_disabled_and_save(&name, true),
+                output,
+                &format!("Could not disable {name}"),
+            ),
+            UriAction::Enable { name } => output::exit_code(
+                uri_config::set_disabled_and_save(&name, false),
+                output,
+                &format!("Could not enable {name}"),
+            ),
         },
         Commands::Lnd { action } => match action {
             LndAction::Set {
+                node,
                 cert,
                 macaroon,
                 uri,
-            } => lnd_config::store(&cert, &macaroon, &uri),
-            LndAction::Info => lnd::display_info().await,
+                lndconnect,
+                rest,
+            } => {
+                let transport = if rest {
+                    config::LndTransport::Rest
+                } else {
+                    config::LndTransport::Grpc
+                };
+                match (lndconnect, cert, macaroon, uri) {
+                    (Some(lndconnect), ..) => output::exit_code(
+                        lnd_config::store_lndconnect(node.as_deref(), &lndconnect, transport).await,
+                        output,
+                        "Could not set the LND node",
+                    ),
+                    (None, Some(cert), Some(macaroon), Some(uri)) => output::exit_code(
+                        lnd_config::store(node.as_deref(), &cert, &macaroon, &uri, transport).await,
+                        output,
+                        "Could not set the LND node",
+                    ),
+                    // clap requires the three flags without --lndconnect
+                    _ => ExitCode::FAILURE,
+                }
+            }
+            LndAction::Info { node } => output::exit_code(
+                lnd::display_info(node.as_deref(), output).await,
+                output,
+                "Could not get the node info",
+            ),
+            LndAction::Balance { node } => output::exit_code(
+                lnd::display_balance(node.as_deref(), output).await,
+                output,
+                "Could not get the balance",
+            ),
+            LndAction::Channels { node } => output::exit_code(
+                lnd::display_channels(node.as_deref(), output).await,
+                output,
+                "Could not list the channels",
+            ),
+            LndAction::Invoices { node, limit } => output::exit_code(
+                lnd::display_invoices(node.as_deref(), limit, output).await,
+                output,
+                "Could not list the invoices",
+            ),
+            LndAction::Payments { node, limit } => output::exit_code(
+                lnd::display_payments(node.as_deref(), limit, output).await,
+                output,
+                "Could not list the payments",
+            ),
+            LndAction::BakeMacaroon {
+                connection,
+                file,
+                read_only,
+            } => output::exit_code(
+                lnd_config::bake_macaroon(
+                    connection.as_deref(),
+                    file.as_deref(),
+                    read_only,
+                    output,
+                )
+                .await,
+                output,
+                "Could not bake the macaroon",
+            ),
         },
         Commands::Daemon { action } => match action {
-            DaemonAction::Start { pid_file } => {
-                let _ = nostr::start_deamon(
-                    nostr_config::load_or_generate_keys().expect("Could not retrieve keys"),
-                    &PathBuf::from(pid_file),
+            DaemonAction::Start {
+                pid_file,
+                passphrase_fd,
+            } => {
+                if let Some(fd) = passphrase_fd {
+                    secrets::set_passphrase_fd(fd);
+                }
+                let service_keys = match nostr_config::load_or_generate_keys() {
+                    Ok(service_keys) => service_keys,
+                    Err(e) => {
+                        return output::exit_code(Err(e), output, "Could not retrieve keys");
+                    }
+                };
+                output::exit_code(
+                    nostr::start_deamon(service_keys, &PathBuf::from(pid_file)).await,
+                    output,
+                    "Could not start the daemon",
                 )
-                .await;
-            }
-            DaemonAction::Stop { pid_file } => {
-                let _ = nostr::stop_deamon(&PathBuf::from(pid_file));
             }
+            DaemonAction::Stop { pid_file } => output::exit_code(
+                nostr::stop_deamon(&PathBuf::from(pid_file), output),
+                output,
+                "Could not stop the daemon",
+            ),
             DaemonAction::Status { pid_file } => {
-                let pb = &PathBuf::from(pid_file);
-                let _ = nostr::status_deamon(pb);
+                nostr::status_deamon(&PathBuf::from(pid_file), output)
             }
         },
+        Commands::Config { action } => match action {
+            ConfigAction::Encrypt => output::exit_code(
+                secrets::encrypt_and_save(),
+                output,
+                "Could not encrypt the config",
+            ),
+            ConfigAction::Migrate => output::exit_code(
+                uri_config::migrate_and_save(),
+                output,
+                "Could not migrate the config",
+            ),
+        },
     }
-
-    Ok(())
 }
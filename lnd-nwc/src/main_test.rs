@@ -1,16 +1,15 @@
 use clap::{Parser, Subcommand};
 
-mod config;
-
 use nostr_sdk::prelude::*;
 use nwc::prelude::*;
 
-use crate::config::load_config;
-
 #[derive(Parser)]
 #[command(name = "test")]
 #[command(about = "A test executable")]
 struct Cli {
+    // Connection URI printed by `lnd-nwc uri create`
+    #[arg(short = 'u', long)]
+    uri: String,
     #[command(subcommand)]
     command: Commands,
 }
@@ -26,14 +25,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt().init();
 
     let cli = Cli::parse();
-    let _ = test(cli.command).await;
+    let _ = test(&cli.uri, cli.command).await;
 
     Ok(())
 }
 
-async fn test(command: Commands) -> Result<()> {
-    let cfg = load_config();
-    let uri = NostrWalletConnectURI::parse(cfg.uris.get("test").unwrap()).unwrap();
+async fn test(uri: &str, command: Commands) -> Result<()> {
+    let uri = NostrWalletConnectURI::parse(uri).unwrap();
     let nwc = NWC::new(uri);
 
     tracing::info!("Test for {nwc:?}");
@@ -1,33 +1,48 @@
 use core::fmt;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use std::process;
+use std::process::{self, ExitCode};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use libc;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 
-use futures::future::join_all;
 use nostr_sdk::nips::nip47::{
     Notification as Nip47Notification, NotificationResult, NotificationType, PaymentNotification,
     TransactionState, TransactionType,
 };
 use nostr_sdk::prelude::*;
 
-use crate::config::{Config, load_config};
-use crate::lnd;
+use crate::backend::{self, Backends, WalletBackend};
+use crate::config::{BalanceMode, Config, ConnectionConfig, load_config, store_config};
+use crate::db;
+use crate::ledger;
 use crate::nwc_types;
+use crate::output::{EXIT_NOT_RUNNING, EXIT_STATUS_UNKNOWN, OutputFormat, print_json};
+use crate::uri_config;
+
+const REQUESTS_SUBSCRIPTION_ID: &str = "nwc-requests";
+const CONNECTIONS_RELOAD_SECS: u64 = 30;
+const RELAY_CHECK_SECS: u64 = 5;
+const RECONNECT_MIN_DELAY_SECS: u64 = 1;
+const RECONNECT_MAX_DELAY_SECS: u64 = 300;
+const PAYMENTS_RECONCILE_SECS: u64 = 60;
+// A pending payment still unknown to the node after this long was never sent
+const UNKNOWN_PAYMENT_GRACE_SECS: u64 = 600;
 
 pub async fn start_deamon(service_keys: Keys, pid_file: &PathBuf) -> Result<()> {
-    let cfg = load_config();
+    let mut cfg = load_config();
 
     // Block if already running (pid file exists)
     if !pid_file.as_os_str().is_empty() && Path::new(&pid_file).exists() {
-        tracing::error!(
-            "Daemon already appears to be running (pid file exists at {:?}).",
+        return Err(format!(
+            "Daemon already appears to be running (pid file exists at {:?})",
             pid_file
-        );
-        return Ok(());
+        )
+        .into());
     }
 
     // Store current process id
@@ -40,15 +55,14 @@ pub async fn start_deamon(service_keys: Keys, pid_file: &PathBuf) -> Result<()>
         match fs::OpenOptions::new()
             .write(true)
             .create_new(true)
-            .open(&pid_file)
+            .open(pid_file)
         {
             Ok(mut f) => {
                 let pid = process::id();
                 let _ = writeln!(f, "{pid}");
             }
             Err(e) => {
-                tracing::error!("Could not create pid file {:?}: {e}", pid_file);
-                return Ok(());
+                return Err(format!("Could not create pid file {:?}: {e}", pid_file).into());
             }
         }
     } else {
@@ -57,137 +71,239 @@ pub async fn start_deamon(service_keys: Keys, pid_file: &PathBuf) -> Result<()>
 
     tracing::info!("Starting deamon");
 
+    if !cfg.uris.is_empty() {
+        match uri_config::migrate_legacy_uris(&mut cfg) {
+            Ok(count) => {
+                store_config(&cfg);
+                tracing::info!("Migrated {count} URIs to client pubkeys");
+            }
+            Err(e) => tracing::error!("Could not migrate URIs, they will be ignored: {e}"),
+        }
+    }
+
+    if let Err(e) = db::reset_relays() {
+        tracing::error!("Could not reset relay statuses: {e}");
+    }
+
     post_info_to_all_servers(&service_keys, &cfg).await;
-    tokio::select! {
+    let result = tokio::select! {
         result = handle_all_uri_events(&service_keys, &cfg) => {
-            if let Err(e) = result {
-                tracing::error!("Error while handling URI events: {e}");
-            }
+            result.map_err(|e| format!("Error while handling URI events: {e}").into())
         }
         _ = wait_for_shutdown() => {
             tracing::info!("Shutdown signal received, exiting daemon.");
+            Ok(())
         }
-    }
+    };
 
-    if !pid_file.as_os_str().is_empty() {
-        if let Err(e) = fs::remove_file(&pid_file) {
-            if e.kind() != std::io::ErrorKind::NotFound {
-                tracing::error!("Failed to remove pid file {:?}: {e}", pid_file);
-            }
-        }
+    if !pid_file.as_os_str().is_empty()
+        && let Err(e) = fs::remove_file(pid_file)
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        tracing::error!("Failed to remove pid file {:?}: {e}", pid_file);
     }
 
-    Ok(())
+    result
 }
 
-pub fn stop_deamon(pid_file: &PathBuf) -> Result<()> {
+pub fn stop_deamon(pid_file: &PathBuf, output: OutputFormat) -> Result<()> {
     tracing::info!("Stopping deamon");
 
     if pid_file.as_os_str().is_empty() {
-        eprintln!("pid_file is not configured; cannot stop daemon.");
-        return Ok(());
+        return Err("pid_file is not configured; cannot stop daemon".into());
     }
 
     if !Path::new(&pid_file).exists() {
-        eprintln!(
-            "Daemon is not running (pid file {:?} does not exist).",
+        return Err(format!(
+            "Daemon is not running (pid file {:?} does not exist)",
             pid_file
-        );
-        return Ok(());
+        )
+        .into());
     }
 
-    let pid_str = match fs::read_to_string(&pid_file) {
-        Ok(s) => s,
-        Err(e) => {
-            eprintln!("Could not read pid file {:?}: {e}", pid_file);
-            return Ok(());
-        }
-    };
+    let pid_str = fs::read_to_string(pid_file)
+        .map_err(|e| format!("Could not read pid file {:?}: {e}", pid_file))?;
 
-    let pid: i32 = match pid_str.trim().parse() {
-        Ok(p) => p,
-        Err(e) => {
-            eprintln!("Invalid pid file contents {:?}: {e}", pid_file);
-            return Ok(());
-        }
-    };
+    let pid: i32 = pid_str
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid pid file contents {:?}: {e}", pid_file))?;
 
     // Send SIGTERM to the process
     let rc = unsafe { libc::kill(pid, libc::SIGTERM) };
     if rc != 0 {
         let err = std::io::Error::last_os_error();
-        eprintln!("Failed to stop daemon (pid {pid}): {err}");
 
         // If the process doesn't exist anymore, treat pid file as stale and remove it.
         if err.raw_os_error() == Some(libc::ESRCH) {
-            let _ = fs::remove_file(&pid_file);
+            let _ = fs::remove_file(pid_file);
         }
 
-        return Ok(());
+        return Err(format!("Failed to stop daemon (pid {pid}): {err}").into());
     }
 
     // Remove pid file after signalling
-    if let Err(e) = fs::remove_file(&pid_file) {
-        eprintln!(
+    fs::remove_file(pid_file).map_err(|e| {
+        format!(
             "Stopped daemon (pid {pid}) but failed to remove pid file {:?}: {e}",
             pid_file
-        );
-        return Ok(());
+        )
+    })?;
+
+    let message = format!("Stopped daemon (pid {pid}).");
+    if output.is_json() {
+        print_json(&DaemonStatus {
+            status: DaemonState::Stopped,
+            pid: Some(pid),
+            message,
+            relays: Vec::new(),
+        })
+        .map_err(|e| e.to_string())?;
+    } else {
+        tracing::info!("{message}");
     }
-
-    println!("Stopped daemon (pid {pid}).");
     Ok(())
 }
 
-pub fn status_deamon(pid_file: &PathBuf) -> Result<()> {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum DaemonState {
+    Running,
+    Stopped,
+    Stale,
+    Unknown,
+}
+
+#[derive(Serialize)]
+struct DaemonStatus {
+    status: DaemonState,
+    pid: Option<i32>,
+    message: String,
+    relays: Vec<db::RelayRecord>,
+}
+
+pub fn status_deamon(pid_file: &PathBuf, output: OutputFormat) -> ExitCode {
+    let (state, pid, message) = daemon_state(pid_file);
+    let relays = match state {
+        DaemonState::Running => db::relay_records().unwrap_or_else(|e| {
+            tracing::error!("Could not read relay statuses: {e}");
+            Vec::new()
+        }),
+        _ => Vec::new(),
+    };
+
+    if output.is_json() {
+        let status = DaemonStatus {
+            status: state,
+            pid,
+            message,
+            relays,
+        };
+        if let Err(e) = print_json(&status) {
+            tracing::error!("Could not display the status: {e}");
+        }
+    } else if state == DaemonState::Running {
+        tracing::info!("Status: {message}");
+        display_relays(&relays);
+    } else {
+        tracing::error!("Status: {message}");
+    }
+
+    match state {
+        DaemonState::Running => ExitCode::SUCCESS,
+        DaemonState::Stale => ExitCode::FAILURE,
+        DaemonState::Stopped => ExitCode::from(EXIT_NOT_RUNNING),
+        DaemonState::Unknown => ExitCode::from(EXIT_STATUS_UNKNOWN),
+    }
+}
+
+fn daemon_state(pid_file: &PathBuf) -> (DaemonState, Option<i32>, String) {
     if pid_file.as_os_str().is_empty() {
-        tracing::error!("Status: unknown (pid_file is not configured).");
-        return Ok(());
+        return (
+            DaemonState::Unknown,
+            None,
+            "unknown (pid_file is not configured).".to_string(),
+        );
     }
 
     if !Path::new(&pid_file).exists() {
-        tracing::error!("Status: stopped (no pid file at {:?}).", pid_file);
-        return Ok(());
+        return (
+            DaemonState::Stopped,
+            None,
+            format!("stopped (no pid file at {:?}).", pid_file),
+        );
     }
 
     let mut pid_str = String::new();
-    match fs::File::open(&pid_file).and_then(|mut f| f.read_to_string(&mut pid_str)) {
+    match fs::File::open(pid_file).and_then(|mut f| f.read_to_string(&mut pid_str)) {
         Ok(_) => {}
         Err(e) => {
-            tracing::error!(
-                "Status: unknown (could not read pid file {:?}: {e}).",
-                pid_file
+            return (
+                DaemonState::Unknown,
+                None,
+                format!("unknown (could not read pid file {:?}: {e}).", pid_file),
             );
-            return Ok(());
         }
     }
 
     let pid: i32 = match pid_str.trim().parse() {
         Ok(p) => p,
         Err(e) => {
-            tracing::error!("Status: unknown (invalid pid in {:?}: {e}).", pid_file);
-            return Ok(());
+            return (
+                DaemonState::Unknown,
+                None,
+                format!("unknown (invalid pid in {:?}: {e}).", pid_file),
+            );
         }
     };
 
     // Check whether the PID exists (signal 0 doesn't send a signal)
     let rc = unsafe { libc::kill(pid, 0) };
     if rc == 0 {
-        tracing::info!("Status: running (pid {pid}).");
-        return Ok(());
+        return (
+            DaemonState::Running,
+            Some(pid),
+            format!("running (pid {pid})."),
+        );
     }
 
     let err = std::io::Error::last_os_error();
     if err.raw_os_error() == Some(libc::ESRCH) {
-        tracing::error!(
-            "Status: stale pid file (pid {pid} not running). Remove {:?} if needed.",
-            pid_file
-        );
+        (
+            DaemonState::Stale,
+            Some(pid),
+            format!(
+                "stale pid file (pid {pid} not running). Remove {:?} if needed.",
+                pid_file
+            ),
+        )
     } else {
-        tracing::error!("Status: unknown (pid {pid} check failed: {err}).");
+        (
+            DaemonState::Unknown,
+            Some(pid),
+            format!("unknown (pid {pid} check failed: {err})."),
+        )
     }
+}
 
-    Ok(())
+fn display_relays(relays: &[db::RelayRecord]) {
+    println!("Relays:");
+    if relays.is_empty() {
+        println!("\tEmpty");
+    }
+    for relay in relays {
+        let last_event = relay.last_event_at.map_or("never".to_string(), |at| {
+            Timestamp::from(at).to_human_datetime()
+        });
+        println!(
+            "\t{}: {} (last event: {}, errors: {}, checked: {})",
+            relay.url,
+            relay.status,
+            last_event,
+            relay.error_count,
+            Timestamp::from(relay.updated_at).to_human_datetime()
+        );
+    }
 }
 
 #[derive(Debug)]
@@ -207,6 +323,66 @@ impl fmt::Display for Error {
     }
 }
 
+// A configured NWC connection as seen by the daemon
+#[derive(Debug, Clone)]
+struct Connection {
+    name: String,
+    client_pubkey: PublicKey,
+    relays: Vec<RelayUrl>,
+    config: ConnectionConfig,
+}
+
+// Connections by client pubkey, the author of their requests
+type ConnectionMap = HashMap<PublicKey, Connection>;
+
+// Expired connections are kept so their requests are refused, the CLI removes them
+fn load_connections(cfg: &Config) -> Vec<Connection> {
+    cfg.connections
+        .iter()
+        .filter_map(|(name, connection)| {
+            let client_pubkey = match PublicKey::from_hex(&connection.client_pubkey) {
+                Ok(client_pubkey) => client_pubkey,
+                Err(e) => {
+                    tracing::error!("Ignoring {name}, invalid client pubkey: {e}");
+                    return None;
+                }
+            };
+            let relays = connection
+                .relays
+                .iter()
+                .filter_map(|relay| match RelayUrl::parse(relay) {
+                    Ok(relay) => Some(relay),
+                    Err(e) => {
+                        tracing::error!("Ignoring relay {relay} of {name}: {e}");
+                        None
+                    }
+                })
+                .collect();
+
+            Some(Connection {
+                name: name.clone(),
+                client_pubkey,
+                relays,
+                config: connection.clone(),
+            })
+        })
+        .collect()
+}
+
+fn connection_map(cfg: &Config) -> ConnectionMap {
+    load_connections(cfg)
+        .into_iter()
+        .map(|connection| (connection.client_pubkey, connection))
+        .collect()
+}
+
+fn relays_of(connections: &ConnectionMap) -> HashSet<RelayUrl> {
+    connections
+        .values()
+        .flat_map(|connection| connection.relays.clone())
+        .collect()
+}
+
 async fn wait_for_shutdown() {
     #[cfg(unix)]
     {
@@ -243,22 +419,13 @@ async fn wait_for_shutdown() {
 
 async fn post_info_to_all_servers(keys: &Keys, cfg: &Config) {
     let client = Client::new(keys.clone());
-
-    let nwc_uris = cfg
-        .uris
-        .values()
-        .map(|uri| NostrWalletConnectURI::parse(uri.clone()))
-        .filter_map(Result::ok)
-        .collect::<Vec<_>>();
-
-    for relay_url in nwc_uris
-        .iter()
-        .flat_map(|uri| uri.relays.clone())
-        .collect::<HashSet<_>>()
-    {
-        client.add_relay(&relay_url).await.unwrap();
-    }
-
+    add_relays(
+        &client,
+        load_connections(cfg)
+            .into_iter()
+            .flat_map(|connection| connection.relays),
+    )
+    .await;
     client.connect().await;
 
     let content = nwc_types::NwcResponse::default_responses()
@@ -270,61 +437,65 @@ async fn post_info_to_all_servers(keys: &Keys, cfg: &Config) {
         TagKind::Custom("encryption".into()),
         ["nip44_v2"],
     ));
-    let output = client.send_event_builder(builder).await.unwrap();
+    let output = match client.send_event_builder(builder).await {
+        Ok(output) => output,
+        Err(e) => {
+            tracing::error!("Could not post info event: {e}");
+            return;
+        }
+    };
 
     if !output.failed.is_empty() {
+        record_relay_errors(output.failed.keys());
         tracing::debug!("Post info event to server success: {:?}", output.success);
         tracing::debug!("Post info event to server failed: {:?}", output.failed);
     }
 }
 
 async fn handle_all_uri_events(service_keys: &Keys, cfg: &Config) -> Result<(), Error> {
-    let nwc_uris = cfg
-        .uris
-        .values()
-        .map(|uri| NostrWalletConnectURI::parse(uri.clone()))
-        .filter_map(Result::ok)
-        .collect::<Vec<_>>();
-
+    let connections = connection_map(cfg);
+    let subscription_id = SubscriptionId::new(REQUESTS_SUBSCRIPTION_ID);
     let timestamp = Timestamp::now();
 
-    let client = Client::default();
-    for relay_url in nwc_uris
-        .iter()
-        .flat_map(|uri| uri.relays.clone())
-        .collect::<HashSet<_>>()
-    {
-        client.add_relay(&relay_url).await.unwrap();
-    }
-    client.connect().await;
-
-    let filters = nwc_uris
-        .iter()
-        .map(|nwc_uri| {
-            Filter::new()
-                .pubkey(nwc_uri.public_key)
-                .kind(Kind::WalletConnectRequest)
-                .since(timestamp)
-        })
-        .collect::<Vec<_>>();
+    let backends = Arc::new(Backends::from_config(cfg));
 
-    let subscription_ids = join_all(
-        filters
-            .iter()
-            .map(|filter| client.subscribe(filter.clone(), None))
-            .collect::<Vec<_>>(),
+    let client = Client::default();
+    subscribe_requests(
+        &client,
+        service_keys,
+        &connections,
+        &subscription_id,
+        timestamp,
     )
-    .await
-    .into_iter()
-    .map(|subscription| subscription.map_or(None, |id| Some(id.val)))
-    .collect::<Vec<_>>();
+    .await;
 
-    let uri_id_map: Vec<(NostrWalletConnectURI, Option<SubscriptionId>)> =
-        nwc_uris.into_iter().zip(subscription_ids).collect();
+    let connections = Arc::new(RwLock::new(connections));
+    tokio::spawn(reload_connections(
+        client.clone(),
+        service_keys.clone(),
+        connections.clone(),
+        subscription_id.clone(),
+        timestamp,
+    ));
+    tokio::spawn(monitor_relays(
+        client.clone(),
+        service_keys.clone(),
+        connections.clone(),
+        subscription_id.clone(),
+        timestamp,
+    ));
+    tokio::spawn(reconcile_payments(backends.clone(), connections.clone()));
 
     let result = client
         .handle_notifications(|notification| async {
-            handler(service_keys, notification, &uri_id_map).await;
+            handler(
+                service_keys,
+                &backends,
+                notification,
+                &subscription_id,
+                &connections,
+            )
+            .await;
             Ok(false)
         })
         .await;
@@ -335,16 +506,281 @@ async fn handle_all_uri_events(service_keys: &Keys, cfg: &Config) -> Result<(),
     Ok(())
 }
 
+// One subscription for the requests of every connection, replaced when connections change
+async fn subscribe_requests(
+    client: &Client,
+    service_keys: &Keys,
+    connections: &ConnectionMap,
+    subscription_id: &SubscriptionId,
+    since: Timestamp,
+) {
+    add_relays(client, relays_of(connections)).await;
+    client.connect().await;
+
+    if connections.is_empty() {
+        tracing::info!("No connection configured, waiting for new ones");
+        return;
+    }
+
+    let filter = requests_filter(service_keys, connections, since);
+    if let Err(e) = client
+        .subscribe_with_id(subscription_id.clone(), filter, None)
+        .await
+    {
+        tracing::error!("Could not subscribe to requests: {e}");
+    }
+}
+
+fn requests_filter(service_keys: &Keys, connections: &ConnectionMap, since: Timestamp) -> Filter {
+    Filter::new()
+        .pubkey(service_keys.public_key())
+        .authors(connections.keys().copied())
+        .kind(Kind::WalletConnectRequest)
+        .since(since)
+}
+
+// Invalid or unreachable relays are logged and counted instead of stopping the daemon
+async fn add_relays(client: &Client, relays: impl IntoIterator<Item = RelayUrl>) {
+    for relay_url in relays.into_iter().collect::<HashSet<_>>() {
+        if let Err(e) = client.add_relay(&relay_url).await {
+            tracing::error!("Could not add relay {relay_url}: {e}");
+            record_relay_errors([&relay_url]);
+        }
+    }
+}
+
+fn record_relay_errors<'a>(relays: impl IntoIterator<Item = &'a RelayUrl>) {
+    for relay_url in relays {
+        if let Err(e) = db::record_relay_error(relay_url.as_str()) {
+            tracing::error!("Could not record error of relay {relay_url}: {e}");
+        }
+    }
+}
+
+// Reconnection state of a relay, the delay doubles after each failed attempt
+struct RelayHealth {
+    connected: bool,
+    delay: Duration,
+    next_attempt: Instant,
+}
+
+// Records relay statuses, reconnects dropped relays with exponential backoff and
+// subscribes again once they are back
+async fn monitor_relays(
+    client: Client,
+    service_keys: Keys,
+    connections: Arc<RwLock<ConnectionMap>>,
+    subscription_id: SubscriptionId,
+    since: Timestamp,
+) {
+    let mut health: HashMap<RelayUrl, RelayHealth> = HashMap::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(RELAY_CHECK_SECS));
+
+    loop {
+        interval.tick().await;
+
+        for (relay_url, relay) in client.relays().await {
+            let status = relay.status();
+            if let Err(e) = db::record_relay_status(relay_url.as_str(), &status.to_string()) {
+                tracing::error!("Could not record status of relay {relay_url}: {e}");
+            }
+
+            let state = health.entry(relay_url.clone()).or_insert(RelayHealth {
+                connected: true,
+                delay: Duration::from_secs(RECONNECT_MIN_DELAY_SECS),
+                next_attempt: Instant::now(),
+            });
+
+            if status == RelayStatus::Connected {
+                if !state.connected {
+                    tracing::info!("Relay {relay_url} reconnected, subscribing again");
+                    let filter = {
+                        let connections = connections.read().unwrap();
+                        (!connections.is_empty())
+                            .then(|| requests_filter(&service_keys, &connections, since))
+                    };
+                    if let Some(filter) = filter
+                        && let Err(e) = client
+                            .subscribe_with_id_to(
+                                [relay_url.clone()],
+                                subscription_id.clone(),
+                                filter,
+                                None,
+                            )
+                            .await
+                    {
+                        tracing::error!("Could not subscribe again on {relay_url}: {e}");
+                        record_relay_errors([&relay_url]);
+                    }
+                    state.connected = true;
+                    state.delay = Duration::from_secs(RECONNECT_MIN_DELAY_SECS);
+                }
+                continue;
+            }
+
+            if state.connected {
+                tracing::error!("Relay {relay_url} is {status}");
+                state.connected = false;
+                state.next_attempt = Instant::now();
+                record_relay_errors([&relay_url]);
+            }
+
+            if Instant::now() < state.next_attempt
+                || !matches!(status, RelayStatus::Disconnected | RelayStatus::Terminated)
+            {
+                continue;
+            }
+
+            tracing::info!("Reconnecting to {relay_url}");
+            if let Err(e) = client.connect_relay(relay_url.clone()).await {
+                tracing::error!("Could not reconnect to {relay_url}: {e}");
+                record_relay_errors([&relay_url]);
+            }
+            state.next_attempt = Instant::now() + state.delay;
+            state.delay = (state.delay * 2).min(Duration::from_secs(RECONNECT_MAX_DELAY_SECS));
+        }
+    }
+}
+
+// Picks up connections created, removed or changed while the daemon is running
+async fn reload_connections(
+    client: Client,
+    service_keys: Keys,
+    connections: Arc<RwLock<ConnectionMap>>,
+    subscription_id: SubscriptionId,
+    since: Timestamp,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(CONNECTIONS_RELOAD_SECS));
+    let mut archived = HashSet::new();
+
+    loop {
+        archive_expired_connections(&connections.read().unwrap(), &mut archived);
+        interval.tick().await;
+
+        let updated = connection_map(&load_config());
+        let changed = {
+            let mut current = connections.write().unwrap();
+            let changed = current.keys().collect::<HashSet<_>>()
+                != updated.keys().collect::<HashSet<_>>()
+                || relays_of(&current) != relays_of(&updated);
+            *current = updated.clone();
+            changed
+        };
+
+        if changed {
+            tracing::info!("Connections changed, now serving {}", updated.len());
+            subscribe_requests(&client, &service_keys, &updated, &subscription_id, since).await;
+        }
+    }
+}
+
+// Archives the history of a connection once its expiry date is reached, as the CLI does
+// when it removes the connection from the config. Its requests keep being refused, and
+// its pending payments are reconciled before, the archive would hide them.
+fn archive_expired_connections(connections: &ConnectionMap, archived: &mut HashSet<String>) {
+    let now = Timestamp::now().as_secs();
+    for connection in connections.values() {
+        if !connection.config.is_expired(now) || archived.contains(&connection.name) {
+            continue;
+        }
+        match ledger::pending_payments(&connection.name) {
+            Ok(pending) if pending.is_empty() => {}
+            Ok(_) => continue,
+            Err(e) => {
+                tracing::error!("Could not read the payments of {}: {e}", connection.name);
+                continue;
+            }
+        }
+
+        tracing::info!(
+            "Connection {} expired, archiving its history",
+            connection.name
+        );
+        match db::archive_connection(&connection.name) {
+            Ok(()) => {
+                archived.insert(connection.name.clone());
+            }
+            Err(e) => {
+                tracing::error!("Could not archive the history of {}: {e}", connection.name);
+            }
+        }
+    }
+}
+
+// Settles or releases the payments whose outcome was unknown when they were sent
+async fn reconcile_payments(backends: Arc<Backends>, connections: Arc<RwLock<ConnectionMap>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(PAYMENTS_RECONCILE_SECS));
+
+    loop {
+        interval.tick().await;
+
+        let current = connections
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for connection in current {
+            let result = match backends.for_connection(&connection.config) {
+                Ok(backend) => reconcile_connection_payments(&backend, &connection).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                tracing::error!("Could not reconcile payments of {}: {e}", connection.name);
+            }
+        }
+    }
+}
+
+async fn reconcile_connection_payments(
+    backend: &Arc<dyn WalletBackend>,
+    connection: &Connection,
+) -> backend::BackendResult<()> {
+    let pending = ledger::pending_payments(&connection.name)?;
+    let now = Timestamp::now().as_secs();
+    for entry in pending {
+        // Keysend payments with a preimage picked by the node are left to the operator
+        let Some(payment_hash) = &entry.payment_hash else {
+            continue;
+        };
+
+        match backend.lookup_payment(payment_hash).await? {
+            Some(found) if found.state == backend::PaymentState::Succeeded => {
+                ledger::complete_payment(
+                    entry.id,
+                    payment_hash,
+                    &found.payment.preimage,
+                    found.payment.fees_msat,
+                )?;
+                tracing::info!("Payment {} of {} succeeded", entry.id, connection.name);
+            }
+            Some(found) if found.state == backend::PaymentState::Failed => {
+                ledger::cancel_payment(entry.id)?;
+                tracing::info!("Payment {} of {} failed", entry.id, connection.name);
+            }
+            None if entry.created_at + UNKNOWN_PAYMENT_GRACE_SECS < now => {
+                ledger::cancel_payment(entry.id)?;
+                tracing::info!("Payment {} of {} was never sent", entry.id, connection.name);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
 async fn handler(
     service_keys: &Keys,
+    backends: &Backends,
     notification: RelayPoolNotification,
-    uri_ids: &Vec<(NostrWalletConnectURI, Option<SubscriptionId>)>,
+    requests_id: &SubscriptionId,
+    connections: &RwLock<ConnectionMap>,
 ) {
     tracing::info!("Received notification");
     if let RelayPoolNotification::Event {
+        relay_url,
         subscription_id,
         event,
-        ..
     } = notification
     {
         tracing::info!(
@@ -360,74 +796,264 @@ async fn handler(
             return;
         }
 
-        let uri_id = uri_ids
-            .iter()
-            .filter(|(_, opt_id)| opt_id.as_ref() == Some(&subscription_id))
-            .next();
+        if let Err(e) = event.verify() {
+            tracing::error!("Ignoring event {} with an invalid signature: {e}", event.id);
+            return;
+        }
+
+        if let Err(e) = db::record_relay_event(relay_url.as_str()) {
+            tracing::error!("Could not record event of relay {relay_url}: {e}");
+        }
+
+        if subscription_id != *requests_id {
+            tracing::error!(
+                "Incorrect subscription ID {} vs {}",
+                subscription_id,
+                requests_id
+            );
+            return;
+        }
+
+        // Requests are routed to the connection whose client authored them, the
+        // requests of unknown clients are recorded under their pubkey
+        let connection = connections.read().unwrap().get(&event.pubkey).cloned();
+        let name = match &connection {
+            Some(connection) => connection.name.clone(),
+            None => event.pubkey.to_hex(),
+        };
 
-        tracing::info!("uri_id: {:?}", uri_id);
+        // Only requests encrypted for this wallet service are handled
+        let msg = match nip04::decrypt(service_keys.secret_key(), &event.pubkey, &event.content) {
+            Ok(msg) => msg,
+            Err(e) => {
+                tracing::error!("Impossible to decrypt direct message: {e} for {name}");
+                return;
+            }
+        };
+        let request = nwc_types::NwcRequest::from_value(&msg);
+        let method = match &request {
+            Ok(request) => request.result_type().to_string(),
+            Err(_) => serde_json::from_str::<serde_json::Value>(&msg)
+                .ok()
+                .and_then(|value| value.get("method")?.as_str().map(str::to_string))
+                .unwrap_or_default(),
+        };
 
-        if let Some(nwc_uri) = uri_id.map(|(uri, _)| uri) {
-            let msg = nip04::decrypt(&nwc_uri.secret, &nwc_uri.public_key, &event.content);
-            if let Err(e) = msg {
-                tracing::error!("Impossible to decrypt direct message: {} for {}", e, nwc_uri);
+        // The same request arrives once per relay of the connection, recording it
+        // tells whether another relay already delivered or refused it
+        let recorded_method = Some(method.as_str()).filter(|method| !method.is_empty());
+        match db::record_request(&event.id.to_hex(), &name, recorded_method, &msg) {
+            Ok(false) => {
+                tracing::info!("Ignoring already handled request {}", event.id);
                 return;
             }
+            Ok(true) => {}
+            Err(e) => tracing::error!("Failed to record request {}: {e}", event.id),
+        }
+
+        let refusal = Refusal {
+            service_keys,
+            event: &event,
+            relay_url: &relay_url,
+            connection: &name,
+            method: &method,
+        };
+
+        let Some(connection) = connection else {
+            tracing::error!("Unauthorized request {} from {}", event.id, event.pubkey);
+            refusal
+                .reply_unauthorized("No wallet connection exists for this pubkey")
+                .await;
+            return;
+        };
+
+        let now = Timestamp::now().as_secs();
+        if connection.config.is_expired(now) {
+            tracing::error!("Request {} for expired {}", event.id, connection.name);
+            refusal.reply_unauthorized("Connection expired").await;
+            return;
+        }
+
+        // Disabled connections keep their configuration but are refused
+        if connection.config.disabled {
+            tracing::error!("Request {} for disabled {}", event.id, connection.name);
+            refusal.reply_unauthorized("Connection is disabled").await;
+            return;
+        }
 
-            let request = nwc_types::NwcRequest::from_value(&msg.unwrap());
-            if let Err(e) = request {
-                tracing::error!("Impossible to retrieve the request {} for {}", e, nwc_uri);
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                tracing::error!(
+                    "Impossible to retrieve the request {} for {}",
+                    e,
+                    connection.name
+                );
                 return;
             }
+        };
 
-            let result =
-                handle_nwc_request(service_keys, &event.id, &request.unwrap(), &nwc_uri).await;
-            if let Err(ref e) = result {
-                tracing::error!("Error while handling the request {} for {}", e, nwc_uri);
+        let backend = match backends.for_connection(&connection.config) {
+            Ok(backend) => backend,
+            Err(e) => {
+                tracing::error!("Request {} for {}: {e}", event.id, connection.name);
+                let code = nwc_types::ErrorCode::Internal;
+                refusal.reply_error(code, &e.to_string()).await;
+                return;
             }
-        } else {
-            tracing::error!("Incorrect subscription ID {} vs {:?}", subscription_id, uri_ids);
+        };
+        let result =
+            handle_nwc_request(service_keys, &backend, &event.id, &request, &connection).await;
+        if let Err(ref e) = result {
+            tracing::error!(
+                "Error while handling the request {} for {}",
+                e,
+                connection.name
+            );
+        }
+    }
+}
+
+// A request refused before reaching the backend
+struct Refusal<'a> {
+    service_keys: &'a Keys,
+    event: &'a Event,
+    relay_url: &'a RelayUrl,
+    connection: &'a str,
+    method: &'a str,
+}
+
+impl Refusal<'_> {
+    async fn reply_unauthorized(&self, message: &str) {
+        self.reply_error(nwc_types::ErrorCode::Unauthorized, message)
+            .await;
+    }
+
+    // Records the error response and sends it on the relay that delivered the request
+    async fn reply_error(&self, code: nwc_types::ErrorCode, message: &str) {
+        let error = nwc_types::NwcError::Code(code, message.to_string());
+        let content = error.to_event_content(self.method).ok();
+        let Some((content, response)) = content.and_then(|content| {
+            let response = create_event(
+                self.service_keys,
+                &content,
+                &self.event.id,
+                &self.event.pubkey,
+            )?;
+            Some((content, response))
+        }) else {
+            tracing::error!("Could not create the response to {}", self.event.id);
+            return;
+        };
+
+        if let Err(e) = db::record_response(
+            &self.event.id.to_hex(),
+            self.connection,
+            self.method,
+            Some(&nwc_types::error_code_name(error.code())),
+            &content,
+        ) {
+            tracing::error!("Failed to record response to {}: {e}", self.event.id);
+        }
+
+        let client = Client::default();
+        if let Err(e) = client.add_relay(self.relay_url.clone()).await {
+            tracing::error!("Could not add relay {}: {e}", self.relay_url);
+            return;
+        }
+        client.connect().await;
+        if let Err(e) = client.send_event(&response).await {
+            tracing::error!("Could not send the response to {}: {e}", self.event.id);
         }
     }
 }
 
 async fn handle_nwc_request(
     service_keys: &Keys,
+    backend: &Arc<dyn WalletBackend>,
     event_id: &EventId,
     request: &nwc_types::NwcRequest,
-    uri: &NostrWalletConnectURI,
+    connection: &Connection,
 ) -> Result<(), Error> {
     let response = match request {
-        nwc_types::NwcRequest::GetInfo(_) => run_get_info().await,
-        nwc_types::NwcRequest::GetBalance(_) => run_get_balance().await,
+        _ if request.is_admin() && !connection.config.admin => Err(nwc_types::NwcError::Code(
+            nwc_types::ErrorCode::Restricted,
+            "The connection has no admin permission".to_string(),
+        )),
+        nwc_types::NwcRequest::GetInfo(_) => run_get_info(connection).await,
+        nwc_types::NwcRequest::GetBalance(_) => run_get_balance(backend, connection).await,
         nwc_types::NwcRequest::PayInvoice(params) => {
-            run_pay_invoice(service_keys, uri, params).await
+            run_pay_invoice(service_keys, backend, connection, params).await
         }
         nwc_types::NwcRequest::PayKeysend(params) => {
-            run_pay_keysend(service_keys, uri, params).await
+            run_pay_keysend(service_keys, backend, connection, params).await
         }
         nwc_types::NwcRequest::MakeInvoice(params) => {
-            run_make_invoice(service_keys, uri, params).await
+            run_make_invoice(service_keys, backend, connection, params).await
         }
         nwc_types::NwcRequest::LookupInvoice(params) => {
-            run_lookup_invoice(service_keys, uri, params).await
+            run_lookup_invoice(service_keys, backend, connection, params).await
+        }
+        nwc_types::NwcRequest::ListTransactions(params) => {
+            run_list_transactions(connection, params).await
+        }
+        nwc_types::NwcRequest::ListChannels(_) => run_list_channels(backend).await,
+        nwc_types::NwcRequest::OpenChannel(params) => {
+            run_open_channel(backend, connection, params).await
+        }
+        nwc_types::NwcRequest::CloseChannel(params) => {
+            run_close_channel(backend, connection, params).await
+        }
+    };
+
+    let error_code = response
+        .as_ref()
+        .err()
+        .map(|e| nwc_types::error_code_name(e.code()));
+    let content = match response {
+        Ok(response) => response.to_event_content(),
+        Err(e) => {
+            tracing::error!(
+                "Request {} failed for {}: {e}",
+                request.result_type(),
+                connection.name
+            );
+            e.to_event_content(request.result_type())
         }
     }
     .map_err(Error::NwcError)?;
 
-    let content = response
-        .to_event_content()
-        .map_err(|e| Error::NwcError(e))?;
+    if let Err(e) = db::record_response(
+        &event_id.to_hex(),
+        &connection.name,
+        request.result_type(),
+        error_code.as_deref(),
+        &content,
+    ) {
+        tracing::error!("Failed to record response to {event_id}: {e}");
+    }
 
     let client = Client::default();
-    for relay_url in uri.relays.iter() {
-        client.add_relay(relay_url.clone()).await.unwrap();
-    }
+    add_relays(&client, connection.relays.clone()).await;
     client.connect().await;
 
-    let event = create_event(service_keys, &content, &event_id.clone(), uri).unwrap();
+    let event = create_event(
+        service_keys,
+        &content,
+        &event_id.clone(),
+        &connection.client_pubkey,
+    )
+    .ok_or_else(|| {
+        Error::NwcError(nwc_types::NwcError::Message(
+            "Could not create the response event".to_string(),
+        ))
+    })?;
     tracing::info!("Ready to send response {}", event.id);
-    client.send_event(&event).await.unwrap();
+    let output = client
+        .send_event(&event)
+        .await
+        .map_err(Error::ClientError)?;
+    record_relay_errors(output.failed.keys());
 
     tracing::info!("Sent response {}", event.id);
     Ok(())
@@ -437,46 +1063,96 @@ fn create_event(
     service_keys: &Keys,
     content: &str,
     event_id: &EventId,
-    uri: &NostrWalletConnectURI,
+    client_pubkey: &PublicKey,
 ) -> Option<Event> {
-    let encrypted = nip04::encrypt(&uri.secret, &uri.public_key, content).unwrap();
+    let encrypted = nip04::encrypt(service_keys.secret_key(), client_pubkey, content).ok()?;
     EventBuilder::new(Kind::WalletConnectResponse, encrypted)
-        .tag(Tag::event(event_id.clone()))
-        .build(uri.public_key)
+        .tag(Tag::public_key(*client_pubkey))
+        .tag(Tag::event(*event_id))
+        .build(service_keys.public_key())
         .sign_with_keys(service_keys)
         .ok()
 }
 
 // Calls
 
-async fn run_get_info() -> Result<nwc_types::NwcResponse, nwc_types::NwcError> {
-    Ok(nwc_types::NwcResponse::GetInfo(nwc_types::GetInfoResult {
-        methods: nwc_types::NwcResponse::default_responses()
-            .iter()
-            .map(|r| r.result_type().to_string())
-            .collect::<Vec<_>>(),
+async fn run_get_info(
+    connection: &Connection,
+) -> Result<nwc_types::NwcResponse, nwc_types::NwcError> {
+    let mut methods = nwc_types::NwcResponse::default_responses()
+        .iter()
+        .map(|r| r.result_type().to_string())
+        .collect::<Vec<_>>();
+    if connection.config.admin {
+        methods.extend(nwc_types::ADMIN_METHODS.map(str::to_string));
+    }
+
+    Ok(nwc_types::NwcResponse::GetInfo(nwc_types::GetInfoResult {
+        methods,
     }))
 }
 
-async fn run_get_balance() -> Result<nwc_types::NwcResponse, nwc_types::NwcError> {
-    let lnd_balance = lnd::channel_balance()
+async fn run_get_balance(
+    backend: &Arc<dyn WalletBackend>,
+    connection: &Connection,
+) -> Result<nwc_types::NwcResponse, nwc_types::NwcError> {
+    if connection.config.sub_wallet {
+        let balance = ledger::balance_msat(&connection.name)
+            .map_err(|e| nwc_types::NwcError::Message(e.to_string()))?;
+        return Ok(nwc_types::NwcResponse::GetBalance(
+            nwc_types::GetBalanceResult { balance },
+        ));
+    }
+
+    let node_balance = backend
+        .balance()
         .await
         .map_err(|e| nwc_types::NwcError::Message(e.to_string()))?;
+    let balance_config = &connection.config.balance;
+    let balance = match balance_config.mode {
+        BalanceMode::Spendable => node_balance.spendable_msat,
+        BalanceMode::Total => node_balance.channels_msat,
+        BalanceMode::WithOnchain => node_balance.channels_msat + node_balance.onchain_msat,
+        BalanceMode::Capped => {
+            let cap = balance_config.cap_msat.ok_or_else(|| {
+                nwc_types::NwcError::Message("The capped balance mode has no cap".to_string())
+            })?;
+            node_balance
+                .spendable_msat
+                .min(i64::try_from(cap).unwrap_or(i64::MAX))
+        }
+    };
+
     Ok(nwc_types::NwcResponse::GetBalance(
-        nwc_types::GetBalanceResult {
-            balance: lnd_balance.local_balance.map_or(0, |balance| balance.msat.cast_signed()),
-        },
+        nwc_types::GetBalanceResult { balance },
     ))
 }
 
 async fn run_pay_invoice(
     service_keys: &Keys,
-    uri: &NostrWalletConnectURI,
+    backend: &Arc<dyn WalletBackend>,
+    connection: &Connection,
     request: &nwc_types::PayInvoiceRequest,
 ) -> Result<nwc_types::NwcResponse, nwc_types::NwcError> {
-    let payment = lnd::pay_invoice(&request.invoice, request.amount)
-        .await
-        .map_err(|e| nwc_types::NwcError::Message(e.to_string()))?;
+    let (payment_hash, amount_msat) = check_invoice_before_payment(backend, request).await?;
+
+    let (id, max_fee_msat) = start_payment_record(
+        connection,
+        Some(request.invoice.clone()),
+        Some(&payment_hash),
+        amount_msat,
+    )?;
+    let result = backend
+        .pay_invoice(
+            &request.invoice,
+            request.amount,
+            &connection.config.payment,
+            max_fee_msat,
+        )
+        .await;
+    finish_payment_record(connection, id, &result);
+
+    let payment = result.map_err(payment_error)?;
 
     let notification = payment_sent_notification(
         &payment,
@@ -486,7 +1162,7 @@ async fn run_pay_invoice(
     );
     if let Err(e) = send_payment_notification(
         service_keys,
-        uri,
+        connection,
         NotificationType::PaymentSent,
         notification,
     )
@@ -497,15 +1173,184 @@ async fn run_pay_invoice(
 
     Ok(nwc_types::NwcResponse::PayInvoice(
         nwc_types::PayInvoiceResult {
-            preimage: payment.payment_preimage.clone(),
-            fees_paid: payment.fee_msat.try_into().ok(),
+            preimage: payment.preimage.clone(),
+            fees_paid: Some(payment.fees_msat),
         },
     ))
 }
 
+// Returns the payment hash and the amount that will be paid, in msat
+async fn check_invoice_before_payment(
+    backend: &Arc<dyn WalletBackend>,
+    request: &nwc_types::PayInvoiceRequest,
+) -> Result<(String, u64), nwc_types::NwcError> {
+    let info = backend
+        .get_info()
+        .await
+        .map_err(|e| nwc_types::NwcError::Message(e.to_string()))?;
+    let node_network = info.network.as_deref();
+    let invoice_network = invoice_network(&request.invoice);
+    if node_network.is_some() && invoice_network != node_network {
+        return Err(nwc_types::NwcError::Code(
+            nwc_types::ErrorCode::Other,
+            format!(
+                "Invoice is for network {} but the node runs on {}",
+                invoice_network.unwrap_or("unknown"),
+                node_network.unwrap_or("unknown")
+            ),
+        ));
+    }
+
+    let pay_req = backend
+        .decode_invoice(&request.invoice)
+        .await
+        .map_err(|e| {
+            nwc_types::NwcError::Code(nwc_types::ErrorCode::Other, format!("Invalid invoice: {e}"))
+        })?;
+
+    let expires_at = pay_req.created_at.saturating_add(pay_req.expiry_secs);
+    if expires_at <= Timestamp::now().as_secs() {
+        return Err(nwc_types::NwcError::Code(
+            nwc_types::ErrorCode::PaymentFailed,
+            format!("Invoice expired at {expires_at}"),
+        ));
+    }
+
+    let invoice_amount_msat = pay_req.amount_msat;
+    match (invoice_amount_msat, request.amount) {
+        (0, None) => {
+            return Err(nwc_types::NwcError::Code(
+                nwc_types::ErrorCode::Other,
+                "Invoice has no amount and no amount was provided".to_string(),
+            ));
+        }
+        (invoice_amount, Some(amount)) if invoice_amount > 0 && invoice_amount != amount => {
+            return Err(nwc_types::NwcError::Code(
+                nwc_types::ErrorCode::Other,
+                format!("Amount {amount} msat does not match invoice amount {invoice_amount} msat"),
+            ));
+        }
+        _ => {}
+    }
+
+    let already_paid = backend
+        .is_payment_succeeded(&pay_req.payment_hash)
+        .await
+        .map_err(|e| nwc_types::NwcError::Message(e.to_string()))?;
+    if already_paid {
+        return Err(nwc_types::NwcError::Code(
+            nwc_types::ErrorCode::PaymentFailed,
+            format!("Invoice {} is already paid", pay_req.payment_hash),
+        ));
+    }
+
+    Ok((
+        pay_req.payment_hash,
+        request.amount.unwrap_or(invoice_amount_msat),
+    ))
+}
+
+// Records the payment before sending it. Sub-wallets also hold the amount on their balance,
+// the returned fee limit keeps the payment within that balance.
+fn start_payment_record(
+    connection: &Connection,
+    invoice: Option<String>,
+    payment_hash: Option<&str>,
+    amount_msat: u64,
+) -> Result<(Option<u64>, Option<u64>), nwc_types::NwcError> {
+    if !connection.config.sub_wallet {
+        let id = ledger::start_payment(&connection.name, invoice, payment_hash, amount_msat);
+        if let Err(ref e) = id {
+            tracing::error!("Failed to record payment of {}: {e}", connection.name);
+        }
+        return Ok((id.ok(), None));
+    }
+
+    let max_fee_msat = backend::default_fee_limit_msat(Some(amount_msat)).cast_unsigned();
+    let reservation = ledger::reserve_payment(
+        &connection.name,
+        invoice,
+        payment_hash,
+        amount_msat,
+        max_fee_msat,
+    )
+    .map_err(|e| nwc_types::NwcError::Message(e.to_string()))?;
+
+    match reservation {
+        Ok((id, fee_reserve)) => Ok((Some(id), Some(fee_reserve))),
+        Err(available) => Err(nwc_types::NwcError::Code(
+            nwc_types::ErrorCode::InsufficientBalance,
+            format!("Insufficient balance: {available} msat available, {amount_msat} msat needed"),
+        )),
+    }
+}
+
+// Only a definitive failure releases the amount held, a payment whose outcome is unknown
+// (e.g. the node could not be reached or the timeout elapsed) stays pending until
+// `reconcile_payments` learns it from the node
+fn finish_payment_record(
+    connection: &Connection,
+    id: Option<u64>,
+    result: &backend::BackendResult<backend::Payment>,
+) {
+    let Some(id) = id else {
+        return;
+    };
+
+    let outcome = match result {
+        Ok(payment) => ledger::complete_payment(
+            id,
+            &payment.payment_hash,
+            &payment.preimage,
+            payment.fees_msat,
+        ),
+        Err(e) if backend::is_payment_failed(e.as_ref()) => ledger::cancel_payment(id),
+        Err(e) => {
+            tracing::warn!(
+                "Outcome of payment {id} of {} is unknown, keeping it pending: {e}",
+                connection.name
+            );
+            Ok(())
+        }
+    };
+    if let Err(e) = outcome {
+        tracing::error!("Failed to update ledger of {}: {e}", connection.name);
+    }
+}
+
+fn payment_error(error: Box<dyn std::error::Error + Send + Sync>) -> nwc_types::NwcError {
+    if backend::is_payment_failed(error.as_ref()) {
+        nwc_types::NwcError::Code(nwc_types::ErrorCode::PaymentFailed, error.to_string())
+    } else {
+        nwc_types::NwcError::Message(format!("Payment outcome is unknown: {error}"))
+    }
+}
+
+// Network of a BOLT11 invoice, named like `backend::NodeInfo::network`
+fn invoice_network(invoice: &str) -> Option<&'static str> {
+    let invoice = invoice.trim().to_lowercase();
+    let invoice = invoice.strip_prefix("lightning:").unwrap_or(&invoice);
+    let currency = invoice.strip_prefix("ln")?;
+
+    if currency.starts_with("bcrt") {
+        Some("regtest")
+    } else if currency.starts_with("bc") {
+        Some("mainnet")
+    } else if currency.starts_with("tbs") {
+        Some("signet")
+    } else if currency.starts_with("tb") {
+        Some("testnet")
+    } else if currency.starts_with("sb") {
+        Some("simnet")
+    } else {
+        None
+    }
+}
+
 async fn run_pay_keysend(
     service_keys: &Keys,
-    uri: &NostrWalletConnectURI,
+    backend: &Arc<dyn WalletBackend>,
+    connection: &Connection,
     request: &nwc_types::PayKeysendRequest,
 ) -> Result<nwc_types::NwcResponse, nwc_types::NwcError> {
     let tlv_records: Vec<(u64, String)> = request
@@ -514,14 +1359,27 @@ async fn run_pay_keysend(
         .map(|record: &nwc_types::KeysendTLVRecord| (record.tlv_type, record.value.clone()))
         .collect();
 
-    let payment = lnd::pay_keysend(
-        &request.pubkey,
-        request.amount,
-        request.preimage.as_deref(),
-        &tlv_records,
-    )
-    .await
-    .map_err(|e| nwc_types::NwcError::Message(e.to_string()))?;
+    // Without a preimage the backend picks one and the hash is only known once paid
+    let payment_hash = request
+        .preimage
+        .as_deref()
+        .and_then(|preimage| hex::decode(preimage).ok())
+        .map(|preimage| hex::encode(Sha256::digest(preimage)));
+    let (id, max_fee_msat) =
+        start_payment_record(connection, None, payment_hash.as_deref(), request.amount)?;
+    let result = backend
+        .pay_keysend(
+            &request.pubkey,
+            request.amount,
+            request.preimage.as_deref(),
+            &tlv_records,
+            &connection.config.payment,
+            max_fee_msat,
+        )
+        .await;
+    finish_payment_record(connection, id, &result);
+
+    let payment = result.map_err(payment_error)?;
 
     let notification = payment_sent_notification(
         &payment,
@@ -531,7 +1389,7 @@ async fn run_pay_keysend(
     );
     if let Err(e) = send_payment_notification(
         service_keys,
-        uri,
+        connection,
         NotificationType::PaymentSent,
         notification,
     )
@@ -542,72 +1400,95 @@ async fn run_pay_keysend(
 
     Ok(nwc_types::NwcResponse::PayKeysend(
         nwc_types::PayKeysendResult {
-            preimage: if payment.payment_preimage.is_empty() {
-                request.preimage.clone().unwrap_or_else(|| "".to_string())
+            preimage: if payment.preimage.is_empty() {
+                request.preimage.clone().unwrap_or_default()
             } else {
-                payment.payment_preimage.clone()
+                payment.preimage.clone()
             },
-            fees_paid: payment.fee_msat.try_into().ok(),
+            fees_paid: Some(payment.fees_msat),
         },
     ))
 }
 
 async fn run_make_invoice(
     service_keys: &Keys,
-    uri: &NostrWalletConnectURI,
+    backend: &Arc<dyn WalletBackend>,
+    connection: &Connection,
     request: &nwc_types::MakeInvoiceRequest,
 ) -> Result<nwc_types::NwcResponse, nwc_types::NwcError> {
-    let invoice = lnd::make_invoice(
+    let invoice = backend
+        .make_invoice(
+            request.amount,
+            request.description.as_deref(),
+            request.description_hash.as_deref(),
+            request.expiry,
+        )
+        .await
+        .map_err(|e| nwc_types::NwcError::Message(e.to_string()))?;
+
+    ledger::add_invoice(
+        &connection.name,
+        &invoice.invoice,
+        request.description.clone(),
+        &invoice.payment_hash,
         request.amount,
-        request.description.as_deref(),
-        request.description_hash.as_deref(),
-        request.expiry,
+        invoice.expires_at,
     )
-    .await
     .map_err(|e| nwc_types::NwcError::Message(e.to_string()))?;
 
-    let payment_hash = hex::encode(invoice.r_hash.clone());
-    let created_at = Timestamp::now();
-    let expires_at = request
-        .expiry
-        .map(|secs| Timestamp::from(created_at.as_secs() + secs));
-
     spawn_payment_received_notifier(
         service_keys.clone(),
-        uri.clone(),
-        invoice.r_hash.clone(),
-        invoice.payment_request.clone(),
+        backend.clone(),
+        connection.clone(),
+        invoice.payment_hash.clone(),
+        invoice.invoice.clone(),
     );
 
     Ok(nwc_types::NwcResponse::MakeInvoice(
         nwc_types::MakeInvoiceResult {
-            invoice: invoice.payment_request,
-            payment_hash: Some(payment_hash),
+            invoice: invoice.invoice,
+            payment_hash: Some(invoice.payment_hash),
             description: request.description.clone(),
             description_hash: request.description_hash.clone(),
             preimage: None,
             amount: Some(request.amount),
-            created_at: Some(created_at),
-            expires_at,
+            created_at: Some(Timestamp::from(invoice.created_at)),
+            expires_at: invoice.expires_at.map(Timestamp::from),
         },
     ))
 }
 
 async fn run_lookup_invoice(
     service_keys: &Keys,
-    uri: &NostrWalletConnectURI,
+    backend: &Arc<dyn WalletBackend>,
+    connection: &Connection,
     request: &nwc_types::LookupInvoiceRequest,
 ) -> Result<nwc_types::NwcResponse, nwc_types::NwcError> {
-    let invoice = lnd::lookup_invoice(request.payment_hash.as_deref(), request.invoice.as_deref())
+    let invoice = backend
+        .lookup_invoice(request.payment_hash.as_deref(), request.invoice.as_deref())
         .await
-        .map_err(|e| nwc_types::NwcError::Message(e.to_string()))?;
-
-    let result = invoice_to_lookup_result(&invoice)?;
+        .map_err(|e| nwc_types::NwcError::Code(nwc_types::ErrorCode::NotFound, e.to_string()))?;
+
+    let result = invoice_to_lookup_result(&invoice);
+
+    if connection.config.sub_wallet {
+        // Sub-wallets only see the invoices they created
+        let entry = ledger::find_by_payment_hash(&connection.name, &result.payment_hash)
+            .map_err(|e| nwc_types::NwcError::Message(e.to_string()))?;
+        if entry.is_none() {
+            return Err(nwc_types::NwcError::Code(
+                nwc_types::ErrorCode::NotFound,
+                "Invoice not found".to_string(),
+            ));
+        }
+    }
 
     if matches!(result.state, Some(TransactionState::Settled)) {
+        record_invoice_settlement(connection, &invoice);
+
         if let Err(e) = send_payment_notification(
             service_keys,
-            uri,
+            connection,
             NotificationType::PaymentReceived,
             payment_received_notification(&invoice),
         )
@@ -620,94 +1501,190 @@ async fn run_lookup_invoice(
     Ok(nwc_types::NwcResponse::LookupInvoice(result))
 }
 
+async fn run_list_transactions(
+    connection: &Connection,
+    request: &nwc_types::ListTransactionsRequest,
+) -> Result<nwc_types::NwcResponse, nwc_types::NwcError> {
+    let filter = ledger::TransactionFilter {
+        from: request.from.map(|t| t.as_secs()),
+        until: request.until.map(|t| t.as_secs()),
+        transaction_type: request.transaction_type,
+        include_unpaid: request.unpaid.unwrap_or(false),
+        limit: request.limit,
+        offset: request.offset,
+    };
+    let transactions = ledger::transactions(&connection.name, &filter)
+        .map_err(|e| nwc_types::NwcError::Message(e.to_string()))?
+        .iter()
+        .map(ledger_entry_to_transaction)
+        .collect();
+
+    Ok(nwc_types::NwcResponse::ListTransactions(
+        nwc_types::ListTransactionsResult { transactions },
+    ))
+}
+
+fn ledger_entry_to_transaction(entry: &ledger::LedgerEntry) -> nwc_types::LookupInvoiceResult {
+    nwc_types::LookupInvoiceResult {
+        transaction_type: Some(entry.transaction_type),
+        state: Some(entry.state),
+        invoice: entry.invoice.clone(),
+        description: entry.description.clone(),
+        description_hash: None,
+        preimage: entry.preimage.clone(),
+        payment_hash: entry.payment_hash.clone().unwrap_or_default(),
+        amount: entry.amount_msat,
+        fees_paid: entry.fees_msat,
+        created_at: Timestamp::from(entry.created_at),
+        expires_at: entry.expires_at.map(Timestamp::from),
+        settled_at: entry.settled_at.map(Timestamp::from),
+        metadata: None,
+    }
+}
+
+// Channel management, the caller checks the admin permission
+
+async fn run_list_channels(
+    backend: &Arc<dyn WalletBackend>,
+) -> Result<nwc_types::NwcResponse, nwc_types::NwcError> {
+    let channels = backend
+        .list_channels()
+        .await
+        .map_err(|e| nwc_types::NwcError::Message(e.to_string()))?;
+
+    Ok(nwc_types::NwcResponse::ListChannels(
+        nwc_types::ListChannelsResult { channels },
+    ))
+}
+
+async fn run_open_channel(
+    backend: &Arc<dyn WalletBackend>,
+    connection: &Connection,
+    request: &nwc_types::OpenChannelRequest,
+) -> Result<nwc_types::NwcResponse, nwc_types::NwcError> {
+    // Node pubkeys are compressed, unlike Nostr ones
+    if !hex::decode(&request.pubkey).is_ok_and(|pubkey| pubkey.len() == 33) {
+        return Err(nwc_types::NwcError::Code(
+            nwc_types::ErrorCode::Other,
+            format!("Invalid node pubkey {}", request.pubkey),
+        ));
+    }
+    if request.amount_sat == 0 || request.push_sat >= request.amount_sat {
+        return Err(nwc_types::NwcError::Code(
+            nwc_types::ErrorCode::Other,
+            "The amount must be positive and above the pushed amount".to_string(),
+        ));
+    }
+
+    tracing::info!(
+        "Opening a {} sat channel to {} for {}",
+        request.amount_sat,
+        request.pubkey,
+        connection.name
+    );
+    let channel_point = backend
+        .open_channel(
+            &request.pubkey,
+            request.host.as_deref(),
+            request.amount_sat,
+            request.push_sat,
+            request.private,
+        )
+        .await
+        .map_err(|e| nwc_types::NwcError::Code(nwc_types::ErrorCode::Other, e.to_string()))?;
+
+    Ok(nwc_types::NwcResponse::OpenChannel(
+        nwc_types::OpenChannelResult { channel_point },
+    ))
+}
+
+async fn run_close_channel(
+    backend: &Arc<dyn WalletBackend>,
+    connection: &Connection,
+    request: &nwc_types::CloseChannelRequest,
+) -> Result<nwc_types::NwcResponse, nwc_types::NwcError> {
+    tracing::info!(
+        "Closing channel {}{} for {}",
+        request.channel_point,
+        if request.force { " by force" } else { "" },
+        connection.name
+    );
+    let closing_txid = backend
+        .close_channel(&request.channel_point, request.force)
+        .await
+        .map_err(|e| nwc_types::NwcError::Code(nwc_types::ErrorCode::Other, e.to_string()))?;
+
+    Ok(nwc_types::NwcResponse::CloseChannel(
+        nwc_types::CloseChannelResult { closing_txid },
+    ))
+}
+
+fn record_invoice_settlement(connection: &Connection, invoice: &backend::Invoice) {
+    if let Err(e) = ledger::settle_invoice(
+        &connection.name,
+        &invoice.payment_hash,
+        invoice.received_msat(),
+        invoice.preimage.clone(),
+        invoice
+            .settled_at
+            .unwrap_or_else(|| Timestamp::now().as_secs()),
+    ) {
+        tracing::error!("Failed to record settlement for {}: {e}", connection.name);
+    }
+}
+
 fn payment_sent_notification(
-    payment: &lnd_grpc_rust::lnrpc::Payment,
+    payment: &backend::Payment,
     transaction_type: TransactionType,
     invoice: String,
     amount_override_msat: Option<u64>,
 ) -> PaymentNotification {
-    let amount_msat = amount_override_msat
-        .or_else(|| payment.value_msat.try_into().ok())
-        .unwrap_or(0);
-    let fee_msat = payment.fee_msat.try_into().ok().unwrap_or(0);
-
-    let created_at = nanos_to_timestamp(payment.creation_time_ns);
-    let settled_at = payment
-        .htlcs
-        .iter()
-        .filter_map(|htlc| nanos_to_timestamp_opt(htlc.resolve_time_ns))
-        .max()
-        .unwrap_or_else(Timestamp::now);
-
     PaymentNotification {
         transaction_type: Some(transaction_type),
         state: Some(TransactionState::Settled),
         invoice,
         description: None,
         description_hash: None,
-        preimage: payment.payment_preimage.clone(),
+        preimage: payment.preimage.clone(),
         payment_hash: payment.payment_hash.clone(),
-        amount: amount_msat,
-        fees_paid: fee_msat,
-        created_at,
+        amount: amount_override_msat.unwrap_or(payment.amount_msat),
+        fees_paid: payment.fees_msat,
+        created_at: if payment.created_at > 0 {
+            Timestamp::from(payment.created_at)
+        } else {
+            Timestamp::now()
+        },
         expires_at: None,
-        settled_at,
+        settled_at: payment
+            .settled_at
+            .map_or_else(Timestamp::now, Timestamp::from),
         metadata: None,
     }
 }
 
-fn payment_received_notification(invoice: &lnd_grpc_rust::lnrpc::Invoice) -> PaymentNotification {
-    let amount_msat = if invoice.amt_paid_msat > 0 {
-        invoice.amt_paid_msat as u64
-    } else {
-        invoice.value_msat as u64
-    };
-    let created_at = Timestamp::from(invoice.creation_date as u64);
-    let expires_at = if invoice.expiry > 0 {
-        Some(Timestamp::from(
-            invoice.creation_date as u64 + invoice.expiry as u64,
-        ))
-    } else {
-        None
-    };
-    let settled_at = if invoice.settle_date > 0 {
-        Some(Timestamp::from(invoice.settle_date as u64))
-    } else {
-        None
-    };
+fn payment_received_notification(invoice: &backend::Invoice) -> PaymentNotification {
+    let created_at = Timestamp::from(invoice.created_at);
 
     PaymentNotification {
         transaction_type: Some(TransactionType::Incoming),
         state: Some(TransactionState::Settled),
-        invoice: invoice.payment_request.clone(),
-        description: if invoice.memo.is_empty() {
-            None
-        } else {
-            Some(invoice.memo.clone())
-        },
-        description_hash: if invoice.description_hash.is_empty() {
-            None
-        } else {
-            Some(hex::encode(&invoice.description_hash))
-        },
-        preimage: if invoice.r_preimage.is_empty() {
-            String::new()
-        } else {
-            hex::encode(&invoice.r_preimage)
-        },
-        payment_hash: hex::encode(&invoice.r_hash),
-        amount: amount_msat,
+        invoice: invoice.invoice.clone(),
+        description: invoice.description.clone(),
+        description_hash: invoice.description_hash.clone(),
+        preimage: invoice.preimage.clone().unwrap_or_default(),
+        payment_hash: invoice.payment_hash.clone(),
+        amount: invoice.received_msat(),
         fees_paid: 0,
         created_at,
-        expires_at,
-        settled_at: settled_at.unwrap_or(created_at),
+        expires_at: invoice.expires_at.map(Timestamp::from),
+        settled_at: invoice.settled_at.map_or(created_at, Timestamp::from),
         metadata: None,
     }
 }
 
 async fn send_payment_notification(
     service_keys: &Keys,
-    uri: &NostrWalletConnectURI,
+    connection: &Connection,
     notification_type: NotificationType,
     notification: PaymentNotification,
 ) -> Result<(), nwc_types::NwcError> {
@@ -721,16 +1698,19 @@ async fn send_payment_notification(
     };
     let content = serde_json::to_string(&nip47_notification)
         .map_err(|e| nwc_types::NwcError::Message(e.to_string()))?;
-    let encrypted = nip04::encrypt(&uri.secret, &uri.public_key, &content)
-        .map_err(|e| nwc_types::NwcError::Message(e.to_string()))?;
+    let encrypted = nip04::encrypt(
+        service_keys.secret_key(),
+        &connection.client_pubkey,
+        &content,
+    )
+    .map_err(|e| nwc_types::NwcError::Message(e.to_string()))?;
 
     let client = Client::default();
-    for relay_url in uri.relays.iter() {
-        client.add_relay(relay_url.clone()).await.unwrap();
-    }
+    add_relays(&client, connection.relays.clone()).await;
     client.connect().await;
 
     let event = EventBuilder::new(Kind::WalletConnectNotification, encrypted)
+        .tag(Tag::public_key(connection.client_pubkey))
         .sign_with_keys(service_keys)
         .map_err(|e| nwc_types::NwcError::Message(e.to_string()))?;
 
@@ -742,85 +1722,79 @@ async fn send_payment_notification(
     Ok(())
 }
 
-fn invoice_to_lookup_result(
-    invoice: &lnd_grpc_rust::lnrpc::Invoice,
-) -> Result<nwc_types::LookupInvoiceResult, nwc_types::NwcError> {
-    let state = match lnd_grpc_rust::lnrpc::invoice::InvoiceState::from_i32(invoice.state) {
-        Some(lnd_grpc_rust::lnrpc::invoice::InvoiceState::Settled) => {
-            Some(TransactionState::Settled)
-        }
-        Some(lnd_grpc_rust::lnrpc::invoice::InvoiceState::Canceled) => {
-            Some(TransactionState::Failed)
-        }
-        Some(lnd_grpc_rust::lnrpc::invoice::InvoiceState::Accepted)
-        | Some(lnd_grpc_rust::lnrpc::invoice::InvoiceState::Open) => {
-            Some(TransactionState::Pending)
-        }
-        _ => None,
-    };
-
-    let amount_msat = if invoice.amt_paid_msat > 0 {
-        invoice.amt_paid_msat as u64
-    } else {
-        invoice.value_msat as u64
+pub(crate) fn invoice_to_lookup_result(
+    invoice: &backend::Invoice,
+) -> nwc_types::LookupInvoiceResult {
+    let state = match invoice.state {
+        backend::InvoiceState::Settled => TransactionState::Settled,
+        backend::InvoiceState::Canceled => TransactionState::Failed,
+        backend::InvoiceState::Open | backend::InvoiceState::Accepted => TransactionState::Pending,
     };
 
-    Ok(nwc_types::LookupInvoiceResult {
+    nwc_types::LookupInvoiceResult {
         transaction_type: Some(TransactionType::Incoming),
-        state,
-        invoice: if invoice.payment_request.is_empty() {
-            None
-        } else {
-            Some(invoice.payment_request.clone())
-        },
-        description: if invoice.memo.is_empty() {
-            None
-        } else {
-            Some(invoice.memo.clone())
-        },
-        description_hash: if invoice.description_hash.is_empty() {
+        state: Some(state),
+        invoice: if invoice.invoice.is_empty() {
             None
         } else {
-            Some(hex::encode(&invoice.description_hash))
+            Some(invoice.invoice.clone())
         },
-        preimage: if invoice.r_preimage.is_empty() {
-            None
-        } else {
-            Some(hex::encode(&invoice.r_preimage))
-        },
-        payment_hash: hex::encode(&invoice.r_hash),
-        amount: amount_msat,
+        description: invoice.description.clone(),
+        description_hash: invoice.description_hash.clone(),
+        preimage: invoice.preimage.clone(),
+        payment_hash: invoice.payment_hash.clone(),
+        amount: invoice.received_msat(),
         fees_paid: 0,
-        created_at: Timestamp::from(invoice.creation_date as u64),
-        expires_at: if invoice.expiry > 0 {
-            Some(Timestamp::from(
-                invoice.creation_date as u64 + invoice.expiry as u64,
-            ))
-        } else {
-            None
-        },
-        settled_at: if invoice.settle_date > 0 {
-            Some(Timestamp::from(invoice.settle_date as u64))
-        } else {
-            None
-        },
+        created_at: Timestamp::from(invoice.created_at),
+        expires_at: invoice.expires_at.map(Timestamp::from),
+        settled_at: invoice.settled_at.map(Timestamp::from),
         metadata: None,
-    })
+    }
+}
+
+pub(crate) fn payment_to_lookup_result(
+    entry: &backend::PaymentEntry,
+) -> nwc_types::LookupInvoiceResult {
+    let payment = &entry.payment;
+    let state = match entry.state {
+        backend::PaymentState::Succeeded => TransactionState::Settled,
+        backend::PaymentState::Failed => TransactionState::Failed,
+        backend::PaymentState::InFlight => TransactionState::Pending,
+    };
+
+    nwc_types::LookupInvoiceResult {
+        transaction_type: Some(TransactionType::Outgoing),
+        state: Some(state),
+        invoice: entry.invoice.clone(),
+        description: None,
+        description_hash: None,
+        preimage: Some(payment.preimage.clone()).filter(|preimage| !preimage.is_empty()),
+        payment_hash: payment.payment_hash.clone(),
+        amount: payment.amount_msat,
+        fees_paid: payment.fees_msat,
+        created_at: Timestamp::from(payment.created_at),
+        expires_at: None,
+        settled_at: payment.settled_at.map(Timestamp::from),
+        metadata: None,
+    }
 }
 
 fn spawn_payment_received_notifier(
     service_keys: Keys,
-    uri: NostrWalletConnectURI,
-    payment_hash: Vec<u8>,
+    backend: Arc<dyn WalletBackend>,
+    connection: Connection,
+    payment_hash: String,
     payment_request: String,
 ) {
     tokio::spawn(async move {
-        match lnd::wait_for_invoice_settlement(payment_hash).await {
+        match backend.wait_for_invoice_settlement(&payment_hash).await {
             Ok(invoice) => {
+                record_invoice_settlement(&connection, &invoice);
+
                 let notification = payment_received_notification(&invoice);
                 if let Err(e) = send_payment_notification(
                     &service_keys,
-                    &uri,
+                    &connection,
                     NotificationType::PaymentReceived,
                     notification,
                 )
@@ -836,14 +1810,410 @@ fn spawn_payment_received_notifier(
     });
 }
 
-fn nanos_to_timestamp(nanos: i64) -> Timestamp {
-    nanos_to_timestamp_opt(nanos).unwrap_or_else(Timestamp::now)
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BalanceConfig, FakeConfig, use_test_config_dir};
+    use crate::fake::FakeBackend;
+
+    fn fake_backend(config: FakeConfig) -> Arc<dyn WalletBackend> {
+        Arc::new(FakeBackend::new(&config))
+    }
+
+    // Tests share the database, each one uses its own connection names
+    fn connection(name: &str, config: ConnectionConfig) -> Connection {
+        use_test_config_dir();
+        Connection {
+            name: name.to_string(),
+            client_pubkey: Keys::generate().public_key(),
+            relays: Vec::new(),
+            config,
+        }
+    }
+
+    fn sub_wallet(name: &str) -> Connection {
+        connection(
+            name,
+            ConnectionConfig {
+                sub_wallet: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    // Handles the request and returns the response recorded for it. Without relays
+    // the response is never sent.
+    async fn request(
+        backend: &Arc<dyn WalletBackend>,
+        connection: &Connection,
+        method: &str,
+        params: serde_json::Value,
+    ) -> serde_json::Value {
+        let event = EventBuilder::text_note(method)
+            .sign_with_keys(&Keys::generate())
+            .unwrap();
+        let content = serde_json::json!({ "method": method, "params": params }).to_string();
+        let request = nwc_types::NwcRequest::from_value(&content).unwrap();
+        let _ =
+            handle_nwc_request(&Keys::generate(), backend, &event.id, &request, connection).await;
+
+        let response: String = db::open()
+            .unwrap()
+            .query_row(
+                "SELECT content FROM responses WHERE request_id = ?1",
+                [event.id.to_hex()],
+                |row| row.get(0),
+            )
+            .unwrap();
+        serde_json::from_str(&response).unwrap()
+    }
 
-fn nanos_to_timestamp_opt(nanos: i64) -> Option<Timestamp> {
-    if nanos <= 0 {
-        return None;
+    fn error_code(response: &serde_json::Value) -> Option<&str> {
+        response["error"]["code"].as_str()
+    }
+
+    fn error_message(response: &serde_json::Value) -> &str {
+        response["error"]["message"].as_str().unwrap_or_default()
+    }
+
+    async fn invoice(backend: &Arc<dyn WalletBackend>, amount_msat: u64) -> String {
+        backend
+            .make_invoice(amount_msat, None, None, Some(3600))
+            .await
+            .unwrap()
+            .invoice
+    }
+
+    async fn balance(backend: &Arc<dyn WalletBackend>, connection: &Connection) -> i64 {
+        let response = request(backend, connection, "get_balance", serde_json::json!({})).await;
+        response["result"]["balance"].as_i64().unwrap()
+    }
+
+    #[tokio::test]
+    async fn sub_wallet_payments_hold_and_release_the_balance() {
+        let backend = fake_backend(FakeConfig::default());
+        let wallet = sub_wallet("ledger-wallet");
+        let payer = connection("ledger-payer", ConnectionConfig::default());
+
+        // Nothing is reserved without funds
+        let bolt11 = invoice(&backend, 4_000).await;
+        let response = request(
+            &backend,
+            &wallet,
+            "pay_invoice",
+            serde_json::json!({ "invoice": bolt11 }),
+        )
+        .await;
+        assert_eq!(error_code(&response), Some("INSUFFICIENT_BALANCE"));
+        assert!(ledger::pending_payments(&wallet.name).unwrap().is_empty());
+
+        // Funded by an invoice of its own
+        let response = request(
+            &backend,
+            &wallet,
+            "make_invoice",
+            serde_json::json!({ "amount": 10_000 }),
+        )
+        .await;
+        let funding = response["result"]["invoice"].as_str().unwrap().to_string();
+        let payment_hash = response["result"]["payment_hash"].as_str().unwrap();
+        let response = request(
+            &backend,
+            &payer,
+            "pay_invoice",
+            serde_json::json!({ "invoice": funding }),
+        )
+        .await;
+        assert_eq!(error_code(&response), None);
+        request(
+            &backend,
+            &wallet,
+            "lookup_invoice",
+            serde_json::json!({ "payment_hash": payment_hash }),
+        )
+        .await;
+        assert_eq!(balance(&backend, &wallet).await, 10_000);
+
+        let response = request(
+            &backend,
+            &wallet,
+            "pay_invoice",
+            serde_json::json!({ "invoice": bolt11 }),
+        )
+        .await;
+        assert_eq!(error_code(&response), None);
+        assert_eq!(balance(&backend, &wallet).await, 6_000);
+
+        // A failed payment gives its amount back
+        let failing = fake_backend(FakeConfig {
+            payment_failure: Some("No route".to_string()),
+            ..Default::default()
+        });
+        let response = request(
+            &failing,
+            &wallet,
+            "pay_keysend",
+            serde_json::json!({ "amount": 1_000, "pubkey": "02".repeat(33) }),
+        )
+        .await;
+        assert_eq!(error_code(&response), Some("PAYMENT_FAILED"));
+        assert_eq!(balance(&backend, &wallet).await, 6_000);
+        assert!(ledger::pending_payments(&wallet.name).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn payments_of_unknown_outcome_are_reconciled() {
+        let backend = fake_backend(FakeConfig::default());
+        let wallet = connection("reconcile-wallet", ConnectionConfig::default());
+
+        let paid = backend.make_invoice(2_000, None, None, None).await.unwrap();
+        backend
+            .pay_invoice(&paid.invoice, None, &Default::default(), None)
+            .await
+            .unwrap();
+        let paid_id = ledger::start_payment(
+            &wallet.name,
+            Some(paid.invoice.clone()),
+            Some(&paid.payment_hash),
+            2_000,
+        )
+        .unwrap();
+        let unknown_hash = "00".repeat(32);
+        ledger::start_payment(&wallet.name, None, Some(&unknown_hash), 3_000).unwrap();
+
+        reconcile_connection_payments(&backend, &wallet)
+            .await
+            .unwrap();
+
+        // The node's payment settles its entry, a recent unknown one is still pending
+        let pending = ledger::pending_payments(&wallet.name).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(
+            pending[0].payment_hash.as_deref(),
+            Some(unknown_hash.as_str())
+        );
+        let settled = ledger::find_by_payment_hash(&wallet.name, &paid.payment_hash)
+            .unwrap()
+            .unwrap();
+        assert_eq!(settled.id, paid_id);
+        assert_eq!(settled.state, TransactionState::Settled);
+        assert_eq!(settled.preimage, paid.preimage);
+    }
+
+    #[test]
+    fn expired_connections_are_archived_once_settled() {
+        let expired = sub_wallet("nostr-expired");
+        let expired = Connection {
+            config: ConnectionConfig {
+                expires_at: Some(1),
+                ..expired.config
+            },
+            ..expired
+        };
+        let active = sub_wallet("nostr-not-expired");
+        let connections = [&expired, &active]
+            .map(|connection| (connection.client_pubkey, connection.clone()))
+            .into_iter()
+            .collect::<ConnectionMap>();
+
+        ledger::add_invoice("nostr-expired", "lnbcrt1", None, "hash-1", 1_000, None).unwrap();
+        ledger::settle_invoice("nostr-expired", "hash-1", 1_000, None, 1).unwrap();
+        ledger::add_invoice("nostr-not-expired", "lnbcrt1", None, "hash-2", 1_000, None).unwrap();
+        ledger::settle_invoice("nostr-not-expired", "hash-2", 1_000, None, 1).unwrap();
+        let payment = ledger::start_payment("nostr-expired", None, Some("hash-3"), 100).unwrap();
+
+        // A pending payment keeps the history until it is reconciled
+        let mut archived = HashSet::new();
+        archive_expired_connections(&connections, &mut archived);
+        assert!(archived.is_empty());
+        assert_eq!(ledger::balance_msat("nostr-expired").unwrap(), 900);
+
+        ledger::cancel_payment(payment).unwrap();
+        archive_expired_connections(&connections, &mut archived);
+        assert!(archived.contains("nostr-expired"));
+        assert_eq!(ledger::balance_msat("nostr-expired").unwrap(), 0);
+        assert_eq!(ledger::balance_msat("nostr-not-expired").unwrap(), 1_000);
+    }
+
+    #[tokio::test]
+    async fn balance_follows_the_connection_mode() {
+        let backend = fake_backend(FakeConfig {
+            balance_msat: 50_000,
+            ..Default::default()
+        });
+        let balance_connection = |name: &str, mode, cap_msat| {
+            connection(
+                name,
+                ConnectionConfig {
+                    balance: BalanceConfig { mode, cap_msat },
+                    ..Default::default()
+                },
+            )
+        };
+
+        let total = balance_connection("balance-total", BalanceMode::Total, None);
+        assert_eq!(balance(&backend, &total).await, 50_000);
+
+        let capped = balance_connection("balance-capped", BalanceMode::Capped, Some(20_000));
+        assert_eq!(balance(&backend, &capped).await, 20_000);
+
+        let above_cap = balance_connection("balance-above-cap", BalanceMode::Capped, Some(90_000));
+        assert_eq!(balance(&backend, &above_cap).await, 50_000);
+
+        // Only a config edited by hand lacks the cap
+        let no_cap = balance_connection("balance-no-cap", BalanceMode::Capped, None);
+        let response = request(&backend, &no_cap, "get_balance", serde_json::json!({})).await;
+        assert_eq!(error_code(&response), Some("INTERNAL"));
+    }
+
+    #[tokio::test]
+    async fn invoices_are_checked_before_paying() {
+        let backend = fake_backend(FakeConfig::default());
+        let wallet = connection("checks-wallet", ConnectionConfig::default());
+        let pay = |bolt11: String| {
+            let backend = backend.clone();
+            let wallet = wallet.clone();
+            async move {
+                request(
+                    &backend,
+                    &wallet,
+                    "pay_invoice",
+                    serde_json::json!({ "invoice": bolt11 }),
+                )
+                .await
+            }
+        };
+
+        // Fake invoices follow the network of their backend
+        let mainnet = fake_backend(FakeConfig {
+            network: "mainnet".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(
+            invoice_network(&invoice(&mainnet, 1_000).await),
+            Some("mainnet")
+        );
+        let bolt11 = invoice(&backend, 1_000).await;
+        let response = request(
+            &mainnet,
+            &wallet,
+            "pay_invoice",
+            serde_json::json!({ "invoice": bolt11 }),
+        )
+        .await;
+        assert_eq!(error_code(&response), Some("OTHER"));
+        assert!(error_message(&response).contains("network regtest"));
+
+        let expired = backend
+            .make_invoice(1_000, None, None, Some(0))
+            .await
+            .unwrap();
+        let response = pay(expired.invoice).await;
+        assert_eq!(error_code(&response), Some("PAYMENT_FAILED"));
+        assert!(error_message(&response).contains("expired"));
+
+        let bolt11 = invoice(&backend, 1_000).await;
+        let response = pay(bolt11.clone()).await;
+        assert_eq!(error_code(&response), None);
+        let response = pay(bolt11).await;
+        assert_eq!(error_code(&response), Some("PAYMENT_FAILED"));
+        assert!(error_message(&response).contains("already paid"));
+
+        // Only the paid invoice reached the ledger
+        let filter = ledger::TransactionFilter {
+            include_unpaid: true,
+            ..Default::default()
+        };
+        let entries = ledger::transactions(&wallet.name, &filter).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].state, TransactionState::Settled);
+    }
+
+    #[tokio::test]
+    async fn list_transactions_applies_the_filters() {
+        let backend = fake_backend(FakeConfig::default());
+        let wallet = connection("list-wallet", ConnectionConfig::default());
+        let payer = connection("list-payer", ConnectionConfig::default());
+
+        let mut hashes = Vec::new();
+        for amount in [10_000, 20_000] {
+            let response = request(
+                &backend,
+                &wallet,
+                "make_invoice",
+                serde_json::json!({ "amount": amount }),
+            )
+            .await;
+            hashes.push(
+                response["result"]["payment_hash"]
+                    .as_str()
+                    .unwrap()
+                    .to_string(),
+            );
+        }
+        let paid = backend
+            .lookup_invoice(Some(&hashes[0]), None)
+            .await
+            .unwrap();
+        request(
+            &backend,
+            &payer,
+            "pay_invoice",
+            serde_json::json!({ "invoice": paid.invoice }),
+        )
+        .await;
+        request(
+            &backend,
+            &wallet,
+            "lookup_invoice",
+            serde_json::json!({ "payment_hash": hashes[0] }),
+        )
+        .await;
+        let bolt11 = invoice(&backend, 5_000).await;
+        request(
+            &backend,
+            &wallet,
+            "pay_invoice",
+            serde_json::json!({ "invoice": bolt11 }),
+        )
+        .await;
+
+        let list = |params: serde_json::Value| {
+            let backend = backend.clone();
+            let wallet = wallet.clone();
+            async move {
+                let response = request(&backend, &wallet, "list_transactions", params).await;
+                response["result"]["transactions"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|transaction| transaction["amount"].as_u64().unwrap())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        // Most recent first, unpaid invoices only on request
+        assert_eq!(list(serde_json::json!({})).await, [5_000, 10_000]);
+        assert_eq!(
+            list(serde_json::json!({ "unpaid": true })).await,
+            [5_000, 20_000, 10_000]
+        );
+        assert_eq!(
+            list(serde_json::json!({ "unpaid": true, "type": "incoming" })).await,
+            [20_000, 10_000]
+        );
+        assert_eq!(
+            list(serde_json::json!({ "type": "outgoing" })).await,
+            [5_000]
+        );
+        assert_eq!(
+            list(serde_json::json!({ "unpaid": true, "limit": 1, "offset": 1 })).await,
+            [20_000]
+        );
+        let future = Timestamp::now().as_secs() + 3600;
+        assert_eq!(
+            list(serde_json::json!({ "unpaid": true, "from": future })).await,
+            Vec::<u64>::new()
+        );
     }
-    let secs = nanos as u128 / 1_000_000_000u128;
-    Some(Timestamp::from(secs as u64))
 }
@@ -7,19 +7,50 @@ use serde_json::json;
 
 use nostr_sdk::prelude::*;
 pub use nostr_sdk::nips::nip47::{
-    KeysendTLVRecord, LookupInvoiceRequest, MakeInvoiceRequest, PayInvoiceRequest,
-    PayKeysendRequest, TransactionState, TransactionType,
+    ErrorCode, KeysendTLVRecord, ListTransactionsRequest, LookupInvoiceRequest, MakeInvoiceRequest,
+    PayInvoiceRequest, PayKeysendRequest, TransactionState, TransactionType,
 };
 
+use crate::backend::Channel;
+
 #[derive(Debug)]
 pub enum NwcError {
     UnknownMethod,
     Json(serde_json::Error),
     Message(String),
+    Code(ErrorCode, String),
+}
+
+impl NwcError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::UnknownMethod => ErrorCode::NotImplemented,
+            Self::Json(_) => ErrorCode::Other,
+            Self::Message(_) => ErrorCode::Internal,
+            Self::Code(code, _) => *code,
+        }
+    }
+
+    pub fn to_event_content(&self, result_type: &str) -> Result<String, NwcError> {
+        let output = serde_json::to_string(&json!({
+            "result_type": result_type,
+            "result": Value::Null,
+            "error": json!({ "code": self.code(), "message": self.to_string() }),
+        }))?;
+        Ok(output)
+    }
 }
 
 impl std::error::Error for NwcError {}
 
+// Name of an error code in NIP-47 responses, e.g. `PAYMENT_FAILED`
+pub fn error_code_name(code: ErrorCode) -> String {
+    serde_json::to_value(code)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
 impl From<serde_json::Error> for NwcError {
     fn from(e: serde_json::Error) -> Self {
         Self::Json(e)
@@ -32,10 +63,14 @@ impl fmt::Display for NwcError {
             Self::UnknownMethod => f.write_str("Unknown method"),
             Self::Json(e) => e.fmt(f),
             Self::Message(msg) => f.write_str(msg),
+            Self::Code(_, msg) => f.write_str(msg),
         }
     }
 }
 
+// Vendor extensions for channel management, only served to admin connections
+pub const ADMIN_METHODS: [&str; 3] = ["list_channels", "open_channel", "close_channel"];
+
 // Requests
 
 pub enum NwcRequest {
@@ -45,12 +80,16 @@ pub enum NwcRequest {
     PayKeysend(PayKeysendRequest),
     MakeInvoice(MakeInvoiceRequest),
     LookupInvoice(LookupInvoiceRequest),
+    ListTransactions(ListTransactionsRequest),
+    ListChannels(ListChannelsRequest),
+    OpenChannel(OpenChannelRequest),
+    CloseChannel(CloseChannelRequest),
 }
 
 #[derive(Serialize, Deserialize)]
 struct RequestTemplate {
-    /// Request method
-    method: Method,
+    /// Request method, a NIP-47 one or a vendor extension
+    method: String,
     /// Params
     #[serde(default)] // handle no params as `Value::Null`
     params: Value,
@@ -60,7 +99,21 @@ impl NwcRequest {
     pub fn from_value(value: &str) -> Result<Self, NwcError> {
         let request: RequestTemplate = serde_json::from_str(value)?;
 
-        match request.method {
+        let method = match request.method.as_str() {
+            "list_channels" => return Ok(Self::ListChannels(ListChannelsRequest {})),
+            "open_channel" => {
+                let params: OpenChannelRequest = serde_json::from_value(request.params)?;
+                return Ok(Self::OpenChannel(params));
+            }
+            "close_channel" => {
+                let params: CloseChannelRequest = serde_json::from_value(request.params)?;
+                return Ok(Self::CloseChannel(params));
+            }
+            method => serde_json::from_value::<Method>(Value::String(method.to_string()))
+                .map_err(|_| NwcError::UnknownMethod)?,
+        };
+
+        match method {
             Method::GetInfo => Ok(Self::GetInfo(GetInfoRequest {})),
             Method::GetBalance => Ok(Self::GetBalance(GetBalanceRequest {})),
             Method::PayInvoice => {
@@ -79,15 +132,63 @@ impl NwcRequest {
                 let params: LookupInvoiceRequest = serde_json::from_value(request.params)?;
                 Ok(Self::LookupInvoice(params))
             }
+            Method::ListTransactions => {
+                let params: ListTransactionsRequest = serde_json::from_value(request.params)?;
+                Ok(Self::ListTransactions(params))
+            }
             _ => Err(NwcError::UnknownMethod),
         }
     }
+
+    pub fn result_type(&self) -> &'static str {
+        match self {
+            Self::GetInfo(_) => "get_info",
+            Self::GetBalance(_) => "get_balance",
+            Self::PayInvoice(_) => "pay_invoice",
+            Self::PayKeysend(_) => "pay_keysend",
+            Self::MakeInvoice(_) => "make_invoice",
+            Self::LookupInvoice(_) => "lookup_invoice",
+            Self::ListTransactions(_) => "list_transactions",
+            Self::ListChannels(_) => "list_channels",
+            Self::OpenChannel(_) => "open_channel",
+            Self::CloseChannel(_) => "close_channel",
+        }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        ADMIN_METHODS.contains(&self.result_type())
+    }
 }
 
 pub struct GetInfoRequest {}
 
 pub struct GetBalanceRequest {}
 
+pub struct ListChannelsRequest {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenChannelRequest {
+    /// Hex encoded node pubkey of the peer
+    pub pubkey: String,
+    /// `host:port` of the peer, connected first when given
+    #[serde(default)]
+    pub host: Option<String>,
+    pub amount_sat: u64,
+    /// Amount given to the peer when the channel opens
+    #[serde(default)]
+    pub push_sat: u64,
+    #[serde(default)]
+    pub private: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CloseChannelRequest {
+    /// `funding_txid:output_index`, as returned by `list_channels`
+    pub channel_point: String,
+    #[serde(default)]
+    pub force: bool,
+}
+
 // Resposne
 #[derive(Debug, Clone)]
 pub enum NwcResponse {
@@ -97,6 +198,10 @@ pub enum NwcResponse {
     PayKeysend(PayKeysendResult),
     MakeInvoice(MakeInvoiceResult),
     LookupInvoice(LookupInvoiceResult),
+    ListTransactions(ListTransactionsResult),
+    ListChannels(ListChannelsResult),
+    OpenChannel(OpenChannelResult),
+    CloseChannel(CloseChannelResult),
 }
 
 impl NwcResponse {
@@ -113,6 +218,10 @@ impl NwcResponse {
             Self::PayKeysend(p) => p.result_type(),
             Self::MakeInvoice(p) => p.result_type(),
             Self::LookupInvoice(p) => p.result_type(),
+            Self::ListTransactions(p) => p.result_type(),
+            Self::ListChannels(p) => p.result_type(),
+            Self::OpenChannel(p) => p.result_type(),
+            Self::CloseChannel(p) => p.result_type(),
         }
     }
 
@@ -123,6 +232,7 @@ impl NwcResponse {
         let pay_keysend = PayKeysendResult::default();
         let make_invoice = MakeInvoiceResult::default();
         let lookup_invoice = LookupInvoiceResult::default();
+        let list_transactions = ListTransactionsResult::default();
         vec![
             NwcResponse::GetInfo(info),
             NwcResponse::GetBalance(balance),
@@ -130,6 +240,7 @@ impl NwcResponse {
             NwcResponse::PayKeysend(pay_keysend),
             NwcResponse::MakeInvoice(make_invoice),
             NwcResponse::LookupInvoice(lookup_invoice),
+            NwcResponse::ListTransactions(list_transactions),
         ]
     }
 
@@ -141,6 +252,10 @@ impl NwcResponse {
             Self::PayKeysend(result) => result.to_content(),
             Self::MakeInvoice(result) => result.to_content(),
             Self::LookupInvoice(result) => result.to_content(),
+            Self::ListTransactions(result) => result.to_content(),
+            Self::ListChannels(result) => result.to_content(),
+            Self::OpenChannel(result) => result.to_content(),
+            Self::CloseChannel(result) => result.to_content(),
         }
     }
 }
@@ -166,7 +281,7 @@ impl GetInfoResult {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct GetBalanceResult {
     pub balance: i64,
 }
@@ -329,3 +444,69 @@ impl LookupInvoiceResult {
         json!({"result_type": self.result_type(), "result": self})
     }
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListTransactionsResult {
+    pub transactions: Vec<LookupInvoiceResult>,
+}
+
+impl ListTransactionsResult {
+    pub fn default() -> Self {
+        Self {
+            transactions: vec![],
+        }
+    }
+
+    fn result_type(&self) -> &'static str {
+        "list_transactions"
+    }
+
+    fn to_content(&self) -> Value {
+        json!({"result_type": self.result_type(), "result": self})
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListChannelsResult {
+    pub channels: Vec<Channel>,
+}
+
+impl ListChannelsResult {
+    fn result_type(&self) -> &'static str {
+        "list_channels"
+    }
+
+    fn to_content(&self) -> Value {
+        json!({"result_type": self.result_type(), "result": self})
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenChannelResult {
+    pub channel_point: String,
+}
+
+impl OpenChannelResult {
+    fn result_type(&self) -> &'static str {
+        "open_channel"
+    }
+
+    fn to_content(&self) -> Value {
+        json!({"result_type": self.result_type(), "result": self})
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CloseChannelResult {
+    pub closing_txid: String,
+}
+
+impl CloseChannelResult {
+    fn result_type(&self) -> &'static str {
+        "close_channel"
+    }
+
+    fn to_content(&self) -> Value {
+        json!({"result_type": self.result_type(), "result": self})
+    }
+}
@@ -0,0 +1,52 @@
+use nostr_sdk::serde_json;
+use serde::Serialize;
+use std::process::ExitCode;
+
+// Format of the command results printed on stdout, logs always go to stderr
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl OutputFormat {
+    pub fn is_json(self) -> bool {
+        self == OutputFormat::Json
+    }
+}
+
+// `daemon status` exit codes follow the LSB init script conventions
+pub const EXIT_NOT_RUNNING: u8 = 3;
+pub const EXIT_STATUS_UNKNOWN: u8 = 4;
+
+#[derive(Serialize)]
+struct ErrorOutput {
+    error: String,
+}
+
+pub fn print_json<T: Serialize>(value: &T) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+// Reports a failed command and turns its result into the process exit code
+pub fn exit_code<E: std::fmt::Display>(
+    result: Result<(), E>,
+    output: OutputFormat,
+    context: &str,
+) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            if output.is_json() {
+                let _ = print_json(&ErrorOutput {
+                    error: format!("{context}: {e}"),
+                });
+            } else {
+                tracing::error!("{context}: {e}");
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
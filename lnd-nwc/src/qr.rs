@@ -0,0 +1,85 @@
+use std::path::{Path, PathBuf};
+
+use image::Luma;
+use qrcode::QrCode;
+use qrcode::render::{svg, unicode};
+
+// Where to render a connection URI as a QR code
+#[derive(Debug, Default)]
+pub struct QrOutput {
+    pub terminal: bool,
+    pub file: Option<PathBuf>,
+}
+
+pub fn output(uri: &str, qr: &QrOutput) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if !qr.terminal && qr.file.is_none() {
+        return Ok(());
+    }
+
+    let code = QrCode::new(uri.as_bytes())?;
+    if qr.terminal {
+        display(&code);
+    }
+    if let Some(file) = &qr.file {
+        export(&code, file)?;
+        tracing::info!("QR code saved to {}", file.display());
+    }
+
+    Ok(())
+}
+
+// Checked before the connection is saved, the URI secret is only displayed once
+pub fn check(qr: &QrOutput) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let Some(file) = &qr.file else {
+        return Ok(());
+    };
+
+    if !matches!(extension(file), Some("png" | "svg")) {
+        return Err(unsupported_file(file));
+    }
+    match file.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() && !parent.is_dir() => {
+            Err(format!("Directory {} does not exist", parent.display()).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn display(code: &QrCode) {
+    // Colors are inverted so the code reads on dark terminal backgrounds
+    let image = code
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build();
+    println!("{image}");
+}
+
+// The format is picked from the file extension
+fn export(code: &QrCode, file: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match extension(file) {
+        Some("png") => code
+            .render::<Luma<u8>>()
+            .min_dimensions(400, 400)
+            .build()
+            .save(file)?,
+        Some("svg") => std::fs::write(
+            file,
+            code.render::<svg::Color>().min_dimensions(400, 400).build(),
+        )?,
+        _ => return Err(unsupported_file(file)),
+    }
+    Ok(())
+}
+
+fn extension(file: &Path) -> Option<&str> {
+    file.extension().and_then(|extension| extension.to_str())
+}
+
+fn unsupported_file(file: &Path) -> Box<dyn std::error::Error + Send + Sync> {
+    format!(
+        "Unsupported QR code file {}, use .png or .svg",
+        file.display()
+    )
+    .into()
+}
@@ -0,0 +1,255 @@
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use secp256k1::rand::{RngCore, rngs::OsRng};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::os::fd::{FromRawFd, RawFd};
+use std::sync::{Mutex, OnceLock};
+
+use crate::config::{Config, EncryptionConfig, load_config, store_config};
+
+// Secrets in the config file are stored as `enc1:<hex(nonce || ciphertext)>`
+// with a key derived from a passphrase (argon2id) and XChaCha20-Poly1305.
+
+type SecretResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+const ENCRYPTED_PREFIX: &str = "enc1:";
+const PASSPHRASE_ENV: &str = "LND_NWC_PASSPHRASE";
+const CHECK_PLAINTEXT: &str = "lnd-nwc";
+
+static PASSPHRASE_FD: Mutex<Option<RawFd>> = Mutex::new(None);
+static FD_PASSPHRASE: OnceLock<String> = OnceLock::new();
+static KEY: OnceLock<[u8; 32]> = OnceLock::new();
+
+// Read the passphrase from this file descriptor instead of the environment or a prompt
+pub fn set_passphrase_fd(fd: RawFd) {
+    *PASSPHRASE_FD.lock().unwrap() = Some(fd);
+}
+
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(ENCRYPTED_PREFIX)
+}
+
+// Returns the plaintext of a config value, unlocking the key if needed
+pub fn reveal(cfg: &Config, value: &str) -> SecretResult<String> {
+    if !is_encrypted(value) {
+        return Ok(value.to_string());
+    }
+
+    let encryption = cfg
+        .encryption
+        .as_ref()
+        .ok_or_else(|| other("Encrypted value found but encryption is not configured"))?;
+    decrypt(&unlock(encryption)?, value)
+}
+
+// Returns the value to store in the config, encrypted when encryption is configured
+pub fn conceal(cfg: &Config, value: &str) -> SecretResult<String> {
+    match &cfg.encryption {
+        Some(encryption) => encrypt(&unlock(encryption)?, value),
+        None => Ok(value.to_string()),
+    }
+}
+
+pub fn encrypt_and_save() -> SecretResult<()> {
+    let mut cfg = load_config();
+    encrypt_config(&mut cfg)?;
+    store_config(&cfg);
+
+    println!("Encrypted the service secret");
+
+    Ok(())
+}
+
+// Encrypts the service secret and any legacy URI of a plaintext config
+fn encrypt_config(cfg: &mut Config) -> SecretResult<()> {
+    if cfg.encryption.is_some() {
+        return Err(Box::new(other("Config secrets are already encrypted")));
+    }
+
+    let passphrase = match read_passphrase_noninteractive()? {
+        Some(passphrase) => passphrase,
+        None => {
+            let passphrase = rpassword::prompt_password("New passphrase: ")?;
+            let confirmation = rpassword::prompt_password("Confirm passphrase: ")?;
+            if passphrase != confirmation {
+                return Err(Box::new(other("Passphrases do not match")));
+            }
+            passphrase
+        }
+    };
+    if passphrase.is_empty() {
+        return Err(Box::new(other("Passphrase must not be empty")));
+    }
+
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let key = derive_key(&passphrase, &salt)?;
+
+    if !cfg.nostr.secret.is_empty() {
+        cfg.nostr.secret = encrypt(&key, &cfg.nostr.secret)?;
+    }
+    for uri in cfg.uris.values_mut() {
+        *uri = encrypt(&key, uri)?;
+    }
+    cfg.encryption = Some(EncryptionConfig {
+        salt: hex::encode(salt),
+        check: encrypt(&key, CHECK_PLAINTEXT)?,
+    });
+    let _ = KEY.set(key);
+
+    Ok(())
+}
+
+fn unlock(encryption: &EncryptionConfig) -> SecretResult<[u8; 32]> {
+    if let Some(key) = KEY.get() {
+        return Ok(*key);
+    }
+
+    let passphrase = match read_passphrase_noninteractive()? {
+        Some(passphrase) => passphrase,
+        None => rpassword::prompt_password("Passphrase: ")?,
+    };
+    let key = check_passphrase(encryption, &passphrase)?;
+
+    Ok(*KEY.get_or_init(|| key))
+}
+
+// Derives the key of the passphrase and checks it against the config
+fn check_passphrase(encryption: &EncryptionConfig, passphrase: &str) -> SecretResult<[u8; 32]> {
+    let salt = hex::decode(&encryption.salt)?;
+    let key = derive_key(passphrase, &salt)?;
+
+    if decrypt(&key, &encryption.check).ok().as_deref() != Some(CHECK_PLAINTEXT) {
+        return Err(Box::new(other("Invalid passphrase")));
+    }
+
+    Ok(key)
+}
+
+fn read_passphrase_noninteractive() -> SecretResult<Option<String>> {
+    // The descriptor is handed over by the caller: it is taken once, owned and closed
+    // by the file below, and the passphrase is kept for the next unlocks
+    let mut passphrase_fd = PASSPHRASE_FD.lock().unwrap();
+    if let Some(passphrase) = FD_PASSPHRASE.get() {
+        return Ok(Some(passphrase.clone()));
+    }
+    if let Some(fd) = passphrase_fd.take() {
+        let file = unsafe { File::from_raw_fd(fd) };
+        let mut line = String::new();
+        BufReader::new(file).read_line(&mut line)?;
+        let passphrase = line.trim_end_matches(['\r', '\n']).to_string();
+        return Ok(Some(FD_PASSPHRASE.get_or_init(|| passphrase).clone()));
+    }
+
+    Ok(std::env::var(PASSPHRASE_ENV).ok())
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> SecretResult<[u8; 32]> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| other(format!("Could not derive key: {e}")))?;
+    Ok(key)
+}
+
+fn encrypt(key: &[u8; 32], plaintext: &str) -> SecretResult<String> {
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+    let mut nonce = [0u8; 24];
+    OsRng.fill_bytes(&mut nonce);
+
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce), plaintext.as_bytes())
+        .map_err(|_| other("Could not encrypt secret"))?;
+
+    let mut payload = nonce.to_vec();
+    payload.extend(ciphertext);
+    Ok(format!("{ENCRYPTED_PREFIX}{}", hex::encode(payload)))
+}
+
+fn decrypt(key: &[u8; 32], value: &str) -> SecretResult<String> {
+    let payload = hex::decode(value.trim_start_matches(ENCRYPTED_PREFIX))?;
+    if payload.len() < 24 {
+        return Err(Box::new(other("Encrypted secret is truncated")));
+    }
+
+    let (nonce, ciphertext) = payload.split_at(24);
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+    let plaintext = cipher
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| other("Could not decrypt secret, wrong passphrase?"))?;
+    Ok(String::from_utf8(plaintext)?)
+}
+
+fn other<E: ToString>(err: E) -> io::Error {
+    io::Error::other(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+    const SALT: [u8; 16] = [7; 16];
+
+    // Tests share the unlocked key, so every encrypted config uses the same passphrase
+    fn encrypted_config() -> Config {
+        let key = derive_key(PASSPHRASE, &SALT).unwrap();
+        let _ = KEY.set(key);
+        Config {
+            encryption: Some(EncryptionConfig {
+                salt: hex::encode(SALT),
+                check: encrypt(&key, CHECK_PLAINTEXT).unwrap(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn concealed_values_are_revealed() {
+        let cfg = encrypted_config();
+        let concealed = conceal(&cfg, "nsec1secret").unwrap();
+        assert_ne!(concealed, "nsec1secret");
+        assert_eq!(reveal(&cfg, &concealed).unwrap(), "nsec1secret");
+
+        // Without encryption values are stored as they are
+        let plain = Config::default();
+        assert_eq!(conceal(&plain, "nsec1secret").unwrap(), "nsec1secret");
+        assert_eq!(reveal(&plain, "nsec1secret").unwrap(), "nsec1secret");
+        assert!(reveal(&plain, &concealed).is_err());
+    }
+
+    #[test]
+    fn wrong_passphrases_are_refused() {
+        let cfg = encrypted_config();
+        let encryption = cfg.encryption.as_ref().unwrap();
+        assert!(check_passphrase(encryption, PASSPHRASE).is_ok());
+        let error = check_passphrase(encryption, "wrong").unwrap_err();
+        assert_eq!(error.to_string(), "Invalid passphrase");
+
+        let concealed = conceal(&cfg, "nsec1secret").unwrap();
+        let wrong_key = derive_key("wrong", &SALT).unwrap();
+        let error = decrypt(&wrong_key, &concealed).unwrap_err();
+        assert!(error.to_string().contains("wrong passphrase"));
+    }
+
+    #[test]
+    fn encrypted_values_are_detected_by_their_prefix() {
+        let concealed = conceal(&encrypted_config(), "nsec1secret").unwrap();
+        assert!(concealed.starts_with("enc1:"));
+        assert!(is_encrypted(&concealed));
+        assert!(!is_encrypted("nsec1secret"));
+        assert!(!is_encrypted(""));
+    }
+
+    #[test]
+    fn encrypted_configs_are_not_encrypted_again() {
+        let mut cfg = encrypted_config();
+        let check = cfg.encryption.as_ref().unwrap().check.clone();
+
+        let error = encrypt_config(&mut cfg).unwrap_err();
+        assert_eq!(error.to_string(), "Config secrets are already encrypted");
+        assert_eq!(cfg.encryption.unwrap().check, check);
+    }
+}
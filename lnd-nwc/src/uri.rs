@@ -1,18 +1,22 @@
-use hex;
 use nostr_sdk::prelude::PublicKey;
 use secp256k1::rand::{RngCore, rngs::OsRng};
 use urlencoding::encode;
 
-pub fn create_uri(public_key: &PublicKey, relay: &str) -> String {
+// One `relay` parameter per relay, as allowed by NIP-47
+pub fn create_uri(public_key: &PublicKey, relays: &[String], secret: &str) -> String {
+    let relays = relays
+        .iter()
+        .map(|relay| format!("relay={}&", encode(relay)))
+        .collect::<String>();
     format!(
-        "nostr+walletconnect://{}?relay={}&secret={}",
+        "nostr+walletconnect://{}?{}secret={}",
         public_key.to_hex(),
-        encode(relay),
-        generate_secret()
+        relays,
+        secret
     )
 }
 
-fn generate_secret() -> String {
+pub fn generate_secret() -> String {
     let mut buf = [0u8; 32];
     let mut rng = OsRng;
     rng.fill_bytes(&mut buf);
@@ -1,51 +1,573 @@
-use crate::config::{load_config, store_config};
-use crate::uri::create_uri;
+use crate::config::{
+    BalanceConfig, BalanceMode, Config, ConnectionConfig, PaymentConfig, load_config, store_config,
+};
+use crate::db;
+use crate::ledger;
+use crate::output::{OutputFormat, print_json};
+use crate::qr::{self, QrOutput};
+use crate::secrets;
+use crate::uri::{create_uri, generate_secret};
 
-use nostr_sdk::{Keys, SecretKey};
+use nostr_sdk::nips::nip47::NostrWalletConnectURI;
+use nostr_sdk::{Keys, RelayUrl, SecretKey, Timestamp};
+use serde::Serialize;
 
-pub fn load_and_display() -> Result<(), Box<dyn std::error::Error>> {
+#[derive(Serialize)]
+struct ConnectionList<'a> {
+    connections: Vec<ConnectionSummary<'a>>,
+    legacy_uris: usize,
+}
+
+#[derive(Serialize)]
+struct ConnectionSummary<'a> {
+    name: &'a str,
+    client_pubkey: &'a str,
+    relays: &'a [String],
+    created_at: u64,
+    disabled: bool,
+    expires_at: Option<u64>,
+    expired: bool,
+}
+
+#[derive(Serialize)]
+struct ConnectionDetails<'a> {
+    #[serde(flatten)]
+    summary: ConnectionSummary<'a>,
+    last_used_at: Option<u64>,
+    sub_wallet: bool,
+    sub_wallet_balance_msat: Option<i64>,
+    lnd_node: Option<&'a str>,
+    admin: bool,
+    balance: &'a BalanceConfig,
+    payment: &'a PaymentConfig,
+}
+
+// Printed once as the secret is not stored
+#[derive(Serialize)]
+struct NewUri<'a> {
+    name: &'a str,
+    uri: &'a str,
+    client_pubkey: &'a str,
+    relays: &'a [String],
+    expires_at: Option<u64>,
+    qr_file: Option<&'a std::path::Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    qr_error: Option<String>,
+}
+
+fn summary<'a>(name: &'a str, connection: &'a ConnectionConfig, now: u64) -> ConnectionSummary<'a> {
+    ConnectionSummary {
+        name,
+        client_pubkey: &connection.client_pubkey,
+        relays: &connection.relays,
+        created_at: connection.created_at,
+        disabled: connection.disabled,
+        expires_at: connection.expires_at,
+        expired: connection.is_expired(now),
+    }
+}
+
+pub fn load_and_display(
+    output: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let cfg = load_config();
 
-    println!("Names and URIs:");
-    if cfg.uris.is_empty() {
+    if output.is_json() {
+        let now = Timestamp::now().as_secs();
+        let mut connections = cfg
+            .connections
+            .iter()
+            .map(|(name, connection)| summary(name, connection, now))
+            .collect::<Vec<_>>();
+        connections.sort_by_key(|connection| connection.name);
+        return print_json(&ConnectionList {
+            connections,
+            legacy_uris: cfg.uris.len(),
+        });
+    }
+
+    println!("Names, client pubkeys and relays:");
+    if cfg.connections.is_empty() {
         println!("\tEmpty");
     } else {
-        for uri in cfg.uris {
-            println!("\t{}: {}", uri.0, uri.1);
+        let now = Timestamp::now().as_secs();
+        for (name, connection) in &cfg.connections {
+            println!(
+                "\t{}: {} ({}){}{}",
+                name,
+                connection.client_pubkey,
+                connection.relays.join(", "),
+                if connection.disabled { " disabled" } else { "" },
+                expiry_label(connection, now)
+            );
         }
     }
-    println!("");
+    if !cfg.uris.is_empty() {
+        println!(
+            "\t{} URIs still store their secret, run `lnd-nwc config migrate`",
+            cfg.uris.len()
+        );
+    }
+    println!();
 
     Ok(())
 }
 
-pub fn create_and_save(name: &str, relay: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let mut cfg = load_config();
-    if cfg.uris.contains_key(name) {
-        panic!("Uri name `{name}`already exists, remove it or use another one")
+pub fn create_and_save(
+    name: &str,
+    relays: &[String],
+    mut connection: ConnectionConfig,
+    qr: &QrOutput,
+    output: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut cfg = load_pruned_config();
+    if cfg.connections.contains_key(name) || cfg.uris.contains_key(name) {
+        return Err(
+            format!("Uri name `{name}` already exists, remove it or use another one").into(),
+        );
+    }
+    if cfg.lnd_node(connection.lnd_node.as_deref()).is_none() {
+        return Err(format!(
+            "LND node `{}` is not configured, add it with `lnd set --node`",
+            connection.lnd_node.as_deref().unwrap_or_default()
+        )
+        .into());
+    }
+    check_connection(&connection)?;
+    qr::check(qr)?;
+    let mut relay_urls: Vec<String> = Vec::new();
+    for relay in relays {
+        let relay = RelayUrl::parse(relay).map_err(|e| format!("Invalid relay {relay}: {e}"))?;
+        if !relay_urls.contains(&relay.to_string()) {
+            relay_urls.push(relay.to_string());
+        }
+    }
+
+    connection.relays = relay_urls;
+    let new_uri = new_client_secret(&cfg, &mut connection)?;
+    connection.created_at = Timestamp::now().as_secs();
+    // History left by a removed connection of the same name
+    db::archive_connection(name)?;
+    let _ = &cfg.connections.insert(name.into(), connection.clone());
+
+    store_config(&cfg);
+
+    display_new_uri(name, &new_uri, &connection, qr, output)
+}
+
+pub fn show(
+    name: &str,
+    output: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let cfg = load_config();
+    let Some(connection) = cfg.connections.get(name) else {
+        return Err(format!("Uri name `{name}` does not exist").into());
+    };
+
+    if output.is_json() {
+        return print_json(&ConnectionDetails {
+            summary: summary(name, connection, Timestamp::now().as_secs()),
+            last_used_at: db::last_request_at(name)?,
+            sub_wallet: connection.sub_wallet,
+            sub_wallet_balance_msat: match connection.sub_wallet {
+                true => Some(ledger::balance_msat(name)?),
+                false => None,
+            },
+            lnd_node: connection.lnd_node.as_deref(),
+            admin: connection.admin,
+            balance: &connection.balance,
+            payment: &connection.payment,
+        });
+    }
+
+    println!("Connection {name}:");
+    println!("\tClient pubkey: {}", connection.client_pubkey);
+    println!("\tRelays: {}", connection.relays.join(", "));
+    println!(
+        "\tCreated: {}",
+        Timestamp::from(connection.created_at).to_human_datetime()
+    );
+    println!(
+        "\tLast used: {}",
+        db::last_request_at(name)?.map_or("never".to_string(), |at| {
+            Timestamp::from(at).to_human_datetime()
+        })
+    );
+    if let Some(expires_at) = connection.expires_at {
+        println!(
+            "\tExpires: {}",
+            Timestamp::from(expires_at).to_human_datetime()
+        );
+    }
+    println!(
+        "\tStatus: {}",
+        if connection.disabled {
+            "disabled"
+        } else {
+            "enabled"
+        }
+    );
+    if let Some(lnd_node) = &connection.lnd_node {
+        println!("\tLND node: {lnd_node}");
+    }
+    if connection.admin {
+        println!("\tAdmin: channel management allowed");
+    }
+    if connection.sub_wallet {
+        println!("\tSub-wallet balance: {} msat", ledger::balance_msat(name)?);
+    }
+    println!("\tBalance mode: {:?}", connection.balance.mode);
+    if let Some(cap_msat) = connection.balance.cap_msat {
+        println!("\tBalance cap: {cap_msat} msat");
+    }
+    println!("\tPayment options: {:?}", connection.payment);
+    println!();
+
+    Ok(())
+}
+
+pub fn rename_and_save(
+    name: &str,
+    new_name: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut cfg = load_pruned_config();
+    if cfg.connections.contains_key(new_name) || cfg.uris.contains_key(new_name) {
+        return Err(
+            format!("Uri name `{new_name}` already exists, remove it or use another one").into(),
+        );
+    }
+    let Some(connection) = cfg.connections.remove(name) else {
+        return Err(format!("Uri name `{name}` does not exist").into());
+    };
+
+    db::archive_connection(new_name)?;
+    db::rename_connection(name, new_name)?;
+    cfg.connections.insert(new_name.into(), connection);
+    store_config(&cfg);
+
+    println!("Renamed {name} to {new_name}");
+
+    Ok(())
+}
+
+// Replaces the client secret, the previous URI stops working
+pub fn rotate_and_save(
+    name: &str,
+    qr: &QrOutput,
+    output: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut cfg = load_pruned_config();
+    let Some(mut connection) = cfg.connections.get(name).cloned() else {
+        return Err(format!("Uri name `{name}` does not exist").into());
+    };
+    qr::check(qr)?;
+
+    let new_uri = new_client_secret(&cfg, &mut connection)?;
+    cfg.connections.insert(name.into(), connection.clone());
+    store_config(&cfg);
+
+    display_new_uri(name, &new_uri, &connection, qr, output)
+}
+
+fn display_new_uri(
+    name: &str,
+    uri: &str,
+    connection: &ConnectionConfig,
+    qr: &QrOutput,
+    output: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // The connection is already saved, a QR code failure must not hide its only URI
+    if output.is_json() {
+        // Only the file export, a terminal QR code would break the JSON output
+        let qr_error = qr::output(
+            uri,
+            &QrOutput {
+                terminal: false,
+                file: qr.file.clone(),
+            },
+        )
+        .err()
+        .map(|e| e.to_string());
+        return print_json(&NewUri {
+            name,
+            uri,
+            client_pubkey: &connection.client_pubkey,
+            relays: &connection.relays,
+            expires_at: connection.expires_at,
+            qr_file: qr.file.as_deref().filter(|_| qr_error.is_none()),
+            qr_error,
+        });
     }
 
-    let public_key = Keys::new(SecretKey::from_hex(&cfg.nostr.secret).unwrap()).public_key();
-    let new_uri = create_uri(&public_key, relay);
-    let _ = &cfg.uris.insert(name.into(), new_uri.clone());
+    println!("New URI for {name}:\n{uri}");
+    println!("The secret is not stored, copy the URI now.");
+    if let Err(e) = qr::output(uri, qr) {
+        tracing::error!("Could not output the QR code: {e}");
+    }
+    Ok(())
+}
+
+pub fn set_disabled_and_save(
+    name: &str,
+    disabled: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut cfg = load_pruned_config();
+    let Some(connection) = cfg.connections.get_mut(name) else {
+        return Err(format!("Uri name `{name}` does not exist").into());
+    };
 
+    connection.disabled = disabled;
     store_config(&cfg);
 
-    println!("New URI created for {name}:\n{new_uri}");
+    if disabled {
+        println!("Disabled URI for {name}");
+    } else {
+        println!("Enabled URI for {name}");
+    }
 
     Ok(())
 }
 
-pub fn remove_and_save(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+// Settings the command line enforces, checked again for configs edited by hand
+fn check_connection(
+    connection: &ConnectionConfig,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if connection.balance.mode == BalanceMode::Capped && connection.balance.cap_msat.is_none() {
+        return Err("The capped balance mode needs a balance cap".into());
+    }
+    if let Some(last_hop) = &connection.payment.last_hop_pubkey {
+        let valid = hex::decode(last_hop)
+            .is_ok_and(|key| key.len() == 33 && secp256k1::PublicKey::from_slice(&key).is_ok());
+        if !valid {
+            return Err(format!("Last hop {last_hop} is not a 33-byte hex node pubkey").into());
+        }
+    }
+    if connection.payment.outgoing_chan_ids.contains(&0) {
+        return Err("Outgoing channel ids must be short channel ids, not 0".into());
+    }
+    Ok(())
+}
+
+fn expiry_label(connection: &ConnectionConfig, now: u64) -> String {
+    match connection.expires_at {
+        Some(_) if connection.is_expired(now) => " expired".to_string(),
+        Some(expires_at) => format!(
+            " expires {}",
+            Timestamp::from(expires_at).to_human_datetime()
+        ),
+        None => String::new(),
+    }
+}
+
+// Generates a client secret for the connection and returns its URI
+fn new_client_secret(
+    cfg: &Config,
+    connection: &mut ConnectionConfig,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let secret_key = SecretKey::from_hex(&secrets::reveal(cfg, &cfg.nostr.secret)?)?;
+    let public_key = Keys::new(secret_key).public_key();
+    let client_secret = generate_secret();
+
+    connection.client_pubkey = Keys::new(SecretKey::from_hex(&client_secret)?)
+        .public_key()
+        .to_hex();
+    Ok(create_uri(&public_key, &connection.relays, &client_secret))
+}
+
+pub fn remove_and_save(name: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let mut cfg = load_config();
-    if cfg.uris.contains_key(name) == false {
-        panic!("Uri name `{name}` does not exist")
+    if !cfg.connections.contains_key(name) && !cfg.uris.contains_key(name) {
+        return Err(format!("Uri name `{name}` does not exist").into());
     }
 
     let _ = &cfg.uris.remove(name);
+    let _ = &cfg.connections.remove(name);
     store_config(&cfg);
+    db::archive_connection(name)?;
 
     println!("Removed URI for {name}");
 
     Ok(())
 }
+
+pub fn migrate_and_save() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut cfg = load_pruned_config();
+    let migrated = migrate_legacy_uris(&mut cfg)?;
+    store_config(&cfg);
+
+    println!("Migrated {migrated} URIs to client pubkeys");
+
+    Ok(())
+}
+
+// Replaces the full URIs of older configs by the client pubkey and relays
+pub fn migrate_legacy_uris(
+    cfg: &mut Config,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    let mut migrated = Vec::new();
+    for (name, stored_uri) in &cfg.uris {
+        let uri = NostrWalletConnectURI::parse(secrets::reveal(cfg, stored_uri)?)?;
+        let mut connection = cfg.connections.get(name).cloned().unwrap_or_default();
+        connection.client_pubkey = Keys::new(uri.secret).public_key().to_hex();
+        connection.relays = uri.relays.iter().map(|relay| relay.to_string()).collect();
+        if connection.created_at == 0 {
+            connection.created_at = Timestamp::now().as_secs();
+        }
+        migrated.push((name.clone(), connection));
+    }
+
+    let count = migrated.len();
+    for (name, connection) in migrated {
+        cfg.uris.remove(&name);
+        cfg.connections.insert(name, connection);
+    }
+
+    Ok(count)
+}
+
+// Config for the commands that save it, pruned of the connections whose expiry date is
+// reached. The daemon refuses their requests but never writes the config.
+fn load_pruned_config() -> Config {
+    let mut cfg = load_config();
+    let expired = remove_expired_connections(&mut cfg);
+    if !expired.is_empty() {
+        tracing::info!("Removed expired connections: {}", expired.join(", "));
+    }
+    cfg
+}
+
+// Removes the connections whose expiry date is reached, their history stays in the database
+fn remove_expired_connections(cfg: &mut Config) -> Vec<String> {
+    let now = Timestamp::now().as_secs();
+    let expired = cfg
+        .connections
+        .iter()
+        .filter(|(_, connection)| connection.is_expired(now))
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    for name in &expired {
+        cfg.connections.remove(name);
+        if let Err(e) = db::archive_connection(name) {
+            tracing::error!("Could not archive the history of {name}: {e}");
+        }
+    }
+    expired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::use_test_config_dir;
+    use crate::nostr_config;
+
+    const NODE_PUBKEY: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+    fn create(name: &str, relays: &[&str], connection: ConnectionConfig) -> Result<(), String> {
+        let relays = relays
+            .iter()
+            .map(|relay| relay.to_string())
+            .collect::<Vec<_>>();
+        create_and_save(
+            name,
+            &relays,
+            connection,
+            &QrOutput::default(),
+            OutputFormat::Json,
+        )
+        .map_err(|e| e.to_string())
+    }
+
+    fn payment(payment: PaymentConfig) -> ConnectionConfig {
+        ConnectionConfig {
+            payment,
+            ..Default::default()
+        }
+    }
+
+    // A single test as the commands load and save the whole config
+    #[test]
+    fn uri_create_checks_and_saves_the_connection() {
+        use_test_config_dir();
+        nostr_config::load_or_generate_keys().unwrap();
+
+        let mut cfg = load_config();
+        cfg.connections.insert(
+            "uri-expired".to_string(),
+            ConnectionConfig {
+                expires_at: Some(1),
+                ..Default::default()
+            },
+        );
+        store_config(&cfg);
+
+        let connection = payment(PaymentConfig {
+            outgoing_chan_ids: vec![123_456],
+            last_hop_pubkey: Some(NODE_PUBKEY.to_string()),
+            ..Default::default()
+        });
+        create(
+            "uri-created",
+            &[
+                "wss://relay.example",
+                "wss://relay.example",
+                "wss://other.example",
+            ],
+            connection,
+        )
+        .unwrap();
+
+        let cfg = load_config();
+        let created = &cfg.connections["uri-created"];
+        assert_eq!(
+            created.relays,
+            ["wss://relay.example", "wss://other.example"]
+        );
+        assert!(nostr_sdk::PublicKey::from_hex(&created.client_pubkey).is_ok());
+        assert!(created.created_at > 0);
+        assert_eq!(
+            created.payment.last_hop_pubkey.as_deref(),
+            Some(NODE_PUBKEY)
+        );
+        // Saving the config pruned the expired connection
+        assert!(!cfg.connections.contains_key("uri-expired"));
+
+        let relays = ["wss://relay.example"];
+        assert!(create("uri-created", &relays, Default::default()).is_err());
+        assert!(create("uri-bad-relay", &["not a relay"], Default::default()).is_err());
+
+        let capped = ConnectionConfig {
+            balance: BalanceConfig {
+                mode: BalanceMode::Capped,
+                cap_msat: None,
+            },
+            ..Default::default()
+        };
+        assert!(create("uri-no-cap", &relays, capped).is_err());
+
+        // Too short, not hex, x-only and with an invalid prefix
+        let invalid_prefix = format!("04{}", &NODE_PUBKEY[2..]);
+        for last_hop in ["abcd", "zz", &NODE_PUBKEY[2..], &invalid_prefix] {
+            let connection = payment(PaymentConfig {
+                last_hop_pubkey: Some(last_hop.to_string()),
+                ..Default::default()
+            });
+            assert!(create("uri-bad-last-hop", &relays, connection).is_err());
+        }
+
+        let connection = payment(PaymentConfig {
+            outgoing_chan_ids: vec![0],
+            ..Default::default()
+        });
+        assert!(create("uri-bad-channel", &relays, connection).is_err());
+
+        let cfg = load_config();
+        for name in [
+            "uri-bad-relay",
+            "uri-no-cap",
+            "uri-bad-last-hop",
+            "uri-bad-channel",
+        ] {
+            assert!(!cfg.connections.contains_key(name), "{name} was saved");
+        }
+    }
+}